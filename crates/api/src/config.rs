@@ -1,13 +1,233 @@
+use anyhow::Context;
 use serde::Deserialize;
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// What a realtime connection does when its outbound queue is full
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued frame; the client silently misses it
+    DropOldest,
+    /// Replace a queued update to the same profile with the new one, and
+    /// disconnect if there's nothing to replace. The client sees a version
+    /// gap and refreshes that profile.
+    #[default]
+    Coalesce,
+    /// Close the connection; the client reconnects and resumes with
+    /// `?since=`
+    Disconnect,
+}
+
+/// How events reach connections, and whether other instances see them
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventBusKind {
+    /// Only connections to this instance see its events
+    #[default]
+    InProcess,
+    /// Every instance polls the shared `events` table
+    Polling,
+    /// Instances share an event log in Postgres and wake each other with
+    /// `NOTIFY`
+    Postgres,
+}
+
+/// Somewhere the server accepts connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// Every address `host` resolves to (an IPv4 or IPv6 address, or a
+    /// hostname), on `port`
+    Tcp { host: String, port: u16 },
+    /// A Unix domain socket, e.g. for a reverse proxy on the same machine
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    /// Parses `host:port`, `[ipv6]:port` or `unix:/path/to/socket`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            anyhow::ensure!(!path.is_empty(), "Missing socket path in {:?}", s);
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .with_context(|| format!("Missing port in listen address {:?}", s))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("Invalid port in listen address {:?}", s))?;
+        let host = match host.strip_prefix('[') {
+            Some(ipv6) => ipv6
+                .strip_suffix(']')
+                .with_context(|| format!("Unclosed '[' in listen address {:?}", s))?,
+            // Bare IPv6 addresses are ambiguous with a port on the end
+            None if host.contains(':') => {
+                anyhow::bail!("IPv6 listen addresses need brackets, e.g. [::1]:3000")
+            }
+            None => host,
+        };
+        anyhow::ensure!(!host.is_empty(), "Missing host in listen address {:?}", s);
+        Ok(Self::Tcp {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Reverse proxies whose `X-Forwarded-For` header is believed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    addrs: Vec<IpAddr>,
+    /// Peers on Unix sockets, which have no address
+    unix: bool,
+}
+
+impl FromStr for TrustedProxies {
+    type Err = anyhow::Error;
+
+    /// Parses comma-separated IP addresses, plus `unix` for Unix sockets
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut proxies = Self::default();
+        for proxy in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            if proxy == "unix" {
+                proxies.unix = true;
+            } else {
+                let addr = proxy
+                    .parse()
+                    .with_context(|| format!("Invalid trusted proxy {:?}", proxy))?;
+                proxies.addrs.push(addr);
+            }
+        }
+        Ok(proxies)
+    }
+}
+
+impl TrustedProxies {
+    /// Whether `peer` is a trusted proxy; `None` is a peer on a Unix socket
+    pub fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(addr) => self.addrs.contains(&addr),
+            None => self.unix,
+        }
+    }
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     #[serde(default = "default_port")]
     pub port: u16,
+    /// Address or hostname to listen on with `port`, unless `listen` is set
     #[serde(default = "default_host")]
     pub host: String,
+    /// Comma-separated addresses to listen on instead of `host` and `port`:
+    /// `host:port`, `[ipv6]:port` or `unix:/path/to/socket`
+    #[serde(default)]
+    pub listen: Option<String>,
+    /// PEM certificate chain to serve HTTPS and WSS with, instead of plain
+    /// HTTP; requires `tls_key_path`. Unix sockets stay plain.
+    #[serde(default)]
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key for `tls_cert_path`
+    #[serde(default)]
+    pub tls_key_path: Option<PathBuf>,
+    /// How often the certificate and key are checked for changes, so a
+    /// renewed certificate is served without a restart
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub tls_reload_interval_secs: u64,
+    /// Comma-separated addresses, like `listen`, to accept plain HTTP on and
+    /// redirect it to HTTPS; only with TLS
+    #[serde(default)]
+    pub tls_redirect_listen: Option<String>,
     #[serde(default = "default_database_url")]
     pub database_url: String,
+    /// Sessions end this long after login, however active they are
+    #[serde(default = "default_session_max_age_secs")]
+    pub session_max_age_secs: u64,
+    /// Sessions end after this long without an authenticated request
+    #[serde(default = "default_session_idle_timeout_secs")]
+    pub session_idle_timeout_secs: u64,
+    /// How often expired sessions are purged from the database
+    #[serde(default = "default_session_sweep_interval_secs")]
+    pub session_sweep_interval_secs: u64,
+    /// How long a ticket from `POST /api/auth/ws-ticket` stays redeemable
+    #[serde(default = "default_ws_ticket_ttl_secs")]
+    pub ws_ticket_ttl_secs: u64,
+    /// Let anonymous clients open `/api/ws` and receive the public feed,
+    /// instead of rejecting the upgrade
+    #[serde(default)]
+    pub ws_public_feed: bool,
+    /// How often the server pings each WebSocket
+    #[serde(default = "default_ws_ping_interval_secs")]
+    pub ws_ping_interval_secs: u64,
+    /// How long a WebSocket has to answer a ping before it's closed as dead
+    #[serde(default = "default_ws_pong_timeout_secs")]
+    pub ws_pong_timeout_secs: u64,
+    /// WebSockets are closed after this long, however active they are;
+    /// clients reconnect and resume with `?since=`
+    #[serde(default = "default_ws_max_lifetime_secs")]
+    pub ws_max_lifetime_secs: u64,
+    /// Frames buffered per connection while the client is slow to read
+    #[serde(default = "default_ws_outbound_queue_capacity")]
+    pub ws_outbound_queue_capacity: usize,
+    /// What happens when a connection's outbound queue is full
+    #[serde(default)]
+    pub ws_overflow_policy: OverflowPolicy,
+    /// Most realtime connections open at once; 0 for no cap
+    #[serde(default = "default_ws_max_connections")]
+    pub ws_max_connections: usize,
+    /// Most realtime connections open at once from one IP address
+    #[serde(default = "default_ws_max_connections_per_ip")]
+    pub ws_max_connections_per_ip: usize,
+    /// Comma-separated addresses of reverse proxies, or `unix` for peers on
+    /// Unix sockets. Requests from them are attributed to the nearest
+    /// address in `X-Forwarded-For` that isn't another of them.
+    #[serde(default)]
+    pub trusted_proxies: Option<String>,
+    /// Most realtime connections open at once by one user
+    #[serde(default = "default_ws_max_connections_per_user")]
+    pub ws_max_connections_per_user: usize,
+    /// Events buffered per subscriber before a slow WebSocket lags and has
+    /// to be resynced
+    #[serde(default = "default_event_channel_capacity")]
+    pub event_channel_capacity: usize,
+    /// Newest events kept in the log for `?since=` resumption; clients
+    /// further behind get a full snapshot
+    #[serde(default = "default_event_log_retention")]
+    pub event_log_retention: i64,
+    /// Patches to the same profile within this many milliseconds are
+    /// merged into one event; 0 disables it
+    #[serde(default)]
+    pub event_coalesce_window_ms: u64,
+    /// How often the event log is compacted down to `event_log_retention`
+    #[serde(default = "default_event_log_compact_interval_secs")]
+    pub event_log_compact_interval_secs: u64,
+    /// How often the relay checks the outbox for events it wasn't woken
+    /// for, e.g. ones left undelivered by a crash
+    #[serde(default = "default_event_relay_poll_interval_ms")]
+    pub event_relay_poll_interval_ms: u64,
+    #[serde(default)]
+    pub event_bus: EventBusKind,
+    /// How often the `polling` bus checks for other instances' events
+    #[serde(default = "default_event_bus_poll_interval_ms")]
+    pub event_bus_poll_interval_ms: u64,
+    /// Postgres holding the shared event log; required by the `postgres` bus
+    #[serde(default)]
+    pub event_bus_postgres_url: Option<String>,
+    /// Bearer token `GET /metrics` requires; without one, metrics aren't
+    /// served at all
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+    /// How long shutdown waits for in-flight requests and realtime
+    /// connections to finish before closing the database anyway
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    pub shutdown_grace_period_secs: u64,
 }
 
 fn default_port() -> u16 {
@@ -18,16 +238,283 @@ fn default_host() -> String {
     "0.0.0.0".to_string()
 }
 
+fn default_tls_reload_interval_secs() -> u64 {
+    10
+}
+
 fn default_database_url() -> String {
     "sqlite:./dev.db".to_string()
 }
 
+fn default_session_max_age_secs() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_session_idle_timeout_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_session_sweep_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_ws_ticket_ttl_secs() -> u64 {
+    30
+}
+
+fn default_ws_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_ws_pong_timeout_secs() -> u64 {
+    10
+}
+
+fn default_ws_max_lifetime_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_ws_outbound_queue_capacity() -> usize {
+    256
+}
+
+fn default_ws_max_connections() -> usize {
+    10_000
+}
+
+fn default_ws_max_connections_per_ip() -> usize {
+    100
+}
+
+fn default_ws_max_connections_per_user() -> usize {
+    20
+}
+
+fn default_event_channel_capacity() -> usize {
+    100
+}
+
+fn default_event_log_retention() -> i64 {
+    10_000
+}
+
+fn default_event_log_compact_interval_secs() -> u64 {
+    5 * 60
+}
+
+fn default_event_relay_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_event_bus_poll_interval_ms() -> u64 {
+    250
+}
+
+fn default_shutdown_grace_period_secs() -> u64 {
+    30
+}
+
+/// Parse a comma-separated list of addresses from the variable `var`
+fn parse_listen_addrs(list: &str, var: &str) -> anyhow::Result<Vec<ListenAddr>> {
+    let addrs = list
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(str::parse)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(!addrs.is_empty(), "{} has no addresses", var);
+    Ok(addrs)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            host: default_host(),
+            listen: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_reload_interval_secs: default_tls_reload_interval_secs(),
+            tls_redirect_listen: None,
+            database_url: default_database_url(),
+            session_max_age_secs: default_session_max_age_secs(),
+            session_idle_timeout_secs: default_session_idle_timeout_secs(),
+            session_sweep_interval_secs: default_session_sweep_interval_secs(),
+            ws_ticket_ttl_secs: default_ws_ticket_ttl_secs(),
+            ws_public_feed: false,
+            ws_ping_interval_secs: default_ws_ping_interval_secs(),
+            ws_pong_timeout_secs: default_ws_pong_timeout_secs(),
+            ws_max_lifetime_secs: default_ws_max_lifetime_secs(),
+            ws_outbound_queue_capacity: default_ws_outbound_queue_capacity(),
+            ws_overflow_policy: OverflowPolicy::default(),
+            ws_max_connections: default_ws_max_connections(),
+            ws_max_connections_per_ip: default_ws_max_connections_per_ip(),
+            ws_max_connections_per_user: default_ws_max_connections_per_user(),
+            trusted_proxies: None,
+            event_channel_capacity: default_event_channel_capacity(),
+            event_log_retention: default_event_log_retention(),
+            event_coalesce_window_ms: 0,
+            event_log_compact_interval_secs: default_event_log_compact_interval_secs(),
+            event_relay_poll_interval_ms: default_event_relay_poll_interval_ms(),
+            event_bus: EventBusKind::default(),
+            event_bus_poll_interval_ms: default_event_bus_poll_interval_ms(),
+            event_bus_postgres_url: None,
+            metrics_token: None,
+            shutdown_grace_period_secs: default_shutdown_grace_period_secs(),
+        }
+    }
+}
+
 impl Config {
     pub fn load() -> anyhow::Result<Self> {
         let config = config::Config::builder()
             .add_source(config::Environment::with_prefix("APP").separator("__"))
             .build()?;
 
-        config.try_deserialize().map_err(Into::into)
+        let config: Self = config.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reject settings the server can't run with, such as a zero-sized
+    /// channel or an interval of zero, rather than panicking later
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let nonzero = [
+            (
+                "APP__EVENT_CHANNEL_CAPACITY",
+                self.event_channel_capacity as u64,
+            ),
+            (
+                "APP__WS_OUTBOUND_QUEUE_CAPACITY",
+                self.ws_outbound_queue_capacity as u64,
+            ),
+            ("APP__WS_PING_INTERVAL_SECS", self.ws_ping_interval_secs),
+            ("APP__WS_PONG_TIMEOUT_SECS", self.ws_pong_timeout_secs),
+            ("APP__WS_MAX_LIFETIME_SECS", self.ws_max_lifetime_secs),
+            (
+                "APP__SESSION_SWEEP_INTERVAL_SECS",
+                self.session_sweep_interval_secs,
+            ),
+            (
+                "APP__EVENT_LOG_COMPACT_INTERVAL_SECS",
+                self.event_log_compact_interval_secs,
+            ),
+            (
+                "APP__EVENT_RELAY_POLL_INTERVAL_MS",
+                self.event_relay_poll_interval_ms,
+            ),
+            (
+                "APP__EVENT_BUS_POLL_INTERVAL_MS",
+                self.event_bus_poll_interval_ms,
+            ),
+            (
+                "APP__TLS_RELOAD_INTERVAL_SECS",
+                self.tls_reload_interval_secs,
+            ),
+        ];
+        for (var, value) in nonzero {
+            anyhow::ensure!(value > 0, "{} must be greater than 0", var);
+        }
+        self.trusted_proxies()?;
+        Ok(())
+    }
+
+    /// The proxies in `trusted_proxies`, if any
+    pub fn trusted_proxies(&self) -> anyhow::Result<TrustedProxies> {
+        match self.trusted_proxies.as_deref() {
+            Some(proxies) => proxies.parse().context("Invalid APP__TRUSTED_PROXIES"),
+            None => Ok(TrustedProxies::default()),
+        }
+    }
+
+    /// Where to accept connections: each address in `listen`, or else
+    /// `host` on `port`
+    pub fn listen_addrs(&self) -> anyhow::Result<Vec<ListenAddr>> {
+        match self.listen.as_deref() {
+            Some(listen) => parse_listen_addrs(listen, "APP__LISTEN"),
+            None => {
+                // Allow `[::1]` as well as `::1`
+                let host = self.host.trim_start_matches('[').trim_end_matches(']');
+                Ok(vec![ListenAddr::Tcp {
+                    host: host.to_string(),
+                    port: self.port,
+                }])
+            }
+        }
+    }
+
+    /// The certificate and key paths if TLS is configured
+    pub fn tls_paths(&self) -> anyhow::Result<Option<(&Path, &Path)>> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => {
+                anyhow::ensure!(
+                    self.tls_redirect_listen.is_none(),
+                    "APP__TLS_REDIRECT_LISTEN requires TLS"
+                );
+                Ok(None)
+            }
+            _ => anyhow::bail!("APP__TLS_CERT_PATH and APP__TLS_KEY_PATH must be set together"),
+        }
+    }
+
+    /// Where to redirect plain HTTP to HTTPS from, if anywhere
+    pub fn tls_redirect_addrs(&self) -> anyhow::Result<Vec<ListenAddr>> {
+        match self.tls_redirect_listen.as_deref() {
+            Some(listen) => parse_listen_addrs(listen, "APP__TLS_REDIRECT_LISTEN"),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn tls_reload_interval(&self) -> Duration {
+        Duration::from_secs(self.tls_reload_interval_secs)
+    }
+
+    pub fn session_max_age(&self) -> Duration {
+        Duration::from_secs(self.session_max_age_secs)
+    }
+
+    pub fn session_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_idle_timeout_secs)
+    }
+
+    pub fn session_sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.session_sweep_interval_secs)
+    }
+
+    pub fn ws_ticket_ttl(&self) -> Duration {
+        Duration::from_secs(self.ws_ticket_ttl_secs)
+    }
+
+    pub fn ws_ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ws_ping_interval_secs)
+    }
+
+    pub fn ws_pong_timeout(&self) -> Duration {
+        Duration::from_secs(self.ws_pong_timeout_secs)
+    }
+
+    pub fn ws_max_lifetime(&self) -> Duration {
+        Duration::from_secs(self.ws_max_lifetime_secs)
+    }
+
+    pub fn event_coalesce_window(&self) -> Duration {
+        Duration::from_millis(self.event_coalesce_window_ms)
+    }
+
+    pub fn event_log_compact_interval(&self) -> Duration {
+        Duration::from_secs(self.event_log_compact_interval_secs)
+    }
+
+    pub fn event_relay_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.event_relay_poll_interval_ms)
+    }
+
+    pub fn event_bus_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.event_bus_poll_interval_ms)
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_period_secs)
     }
 }
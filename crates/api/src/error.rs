@@ -1,8 +1,10 @@
+use crate::limits::LimitExceeded;
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
+use domain::ProfileError;
 use serde_json::json;
 use thiserror::Error;
 
@@ -17,6 +19,12 @@ pub enum AppError {
     #[error("Unauthorized")]
     Unauthorized,
 
+    #[error("Session expired")]
+    SessionExpired,
+
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+
     #[error("Internal error")]
     Internal(#[from] anyhow::Error),
 }
@@ -27,6 +35,8 @@ impl IntoResponse for AppError {
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
             AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
+            AppError::SessionExpired => (StatusCode::UNAUTHORIZED, "Session expired".to_string()),
+            AppError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg.clone()),
             AppError::Internal(e) => {
                 tracing::error!("Internal error: {:?}", e);
                 (
@@ -40,4 +50,20 @@ impl IntoResponse for AppError {
     }
 }
 
+impl From<LimitExceeded> for AppError {
+    fn from(e: LimitExceeded) -> Self {
+        AppError::TooManyRequests(e.to_string())
+    }
+}
+
+impl From<ProfileError> for AppError {
+    fn from(e: ProfileError) -> Self {
+        match e {
+            ProfileError::NotFound => AppError::NotFound("Profile not found".to_string()),
+            ProfileError::NotOwner => AppError::Unauthorized,
+            ProfileError::Database(e) => AppError::Internal(e.into()),
+        }
+    }
+}
+
 pub type AppResult<T> = Result<T, AppError>;
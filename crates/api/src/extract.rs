@@ -0,0 +1,150 @@
+use crate::{config::TrustedProxies, error::AppError, server::UnixPeer, session, state::AppState};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts},
+};
+use db::SessionRow;
+use shared::types::Profile;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+use tower_cookies::Cookies;
+
+/// Who is on the other end of a request, as recorded on new sessions.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+
+        let ip_address = client_ip(parts, &state.trusted_proxies).map(|ip| ip.to_string());
+
+        Ok(Self {
+            user_agent,
+            ip_address,
+        })
+    }
+}
+
+/// The address a request came from. Behind a trusted proxy that's the
+/// nearest address in `X-Forwarded-For` that isn't another trusted proxy,
+/// or the farthest if they all are; otherwise it's the peer's, which
+/// connections over a Unix socket don't have.
+fn client_ip(parts: &Parts, trusted: &TrustedProxies) -> Option<IpAddr> {
+    // Only present when served via `into_make_service_with_connect_info`
+    let peer = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => Some(addr.ip()),
+        None if parts.extensions.get::<ConnectInfo<UnixPeer>>().is_some() => None,
+        None => return None,
+    };
+    if !trusted.trusts(peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<&str> = parts
+        .headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    let mut client = peer;
+    // Each proxy appends who it heard from, so only the entries added by
+    // trusted ones can be believed
+    for hop in forwarded.iter().rev() {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = Some(ip);
+        if !trusted.trusts(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// The authenticated user behind a request, with their profile attached.
+/// Rejects with `Unauthorized` (or `SessionExpired`) when there is no valid
+/// session, so handlers just declare `user: CurrentUser`.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub id: i64,
+    pub email: String,
+    /// The session that authenticated this request
+    pub session_id: i64,
+    pub profile: Profile,
+}
+
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(|(_, msg)| AppError::Internal(anyhow::anyhow!(msg)))?;
+
+        let session = session::current_session(state, &cookies).await?;
+        Self::from_session(state, session).await
+    }
+}
+
+impl CurrentUser {
+    /// Load the user and profile behind an active session
+    pub async fn from_session(state: &AppState, session: SessionRow) -> Result<Self, AppError> {
+        let user = db::get_user_by_id(&state.db, session.user_id)
+            .await
+            .map_err(|e| AppError::Internal(e.into()))?
+            .ok_or(AppError::Unauthorized)?;
+
+        let profile = state
+            .profile_service
+            .get_profile_by_user_id(user.id)
+            .await
+            .map_err(|e| AppError::Internal(e.into()))?
+            .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Profile not found")))?;
+
+        Ok(Self {
+            id: user.id,
+            email: user.email,
+            session_id: session.id,
+            profile,
+        })
+    }
+}
+
+/// Like `CurrentUser`, but anonymous requests get `OptionalUser(None)`
+/// instead of a rejection.
+#[derive(Debug, Clone)]
+pub struct OptionalUser(pub Option<CurrentUser>);
+
+impl FromRequestParts<AppState> for OptionalUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        match CurrentUser::from_request_parts(parts, state).await {
+            Ok(user) => Ok(Self(Some(user))),
+            Err(AppError::Unauthorized | AppError::SessionExpired) => Ok(Self(None)),
+            Err(e) => Err(e),
+        }
+    }
+}
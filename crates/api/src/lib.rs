@@ -1,4 +1,12 @@
 pub mod config;
 pub mod error;
+pub mod extract;
+pub mod limits;
+pub mod metrics;
 pub mod routes;
+pub mod server;
+pub mod session;
+pub mod shutdown;
 pub mod state;
+pub mod tasks;
+pub mod tls;
@@ -0,0 +1,152 @@
+use crate::{config::Config, metrics::Metrics};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// Which cap turned a connection away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum LimitExceeded {
+    #[error("Too many open connections")]
+    Global,
+    #[error("Too many open connections from this address")]
+    PerIp,
+    #[error("Too many open connections for this user")]
+    PerUser,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<String, usize>,
+    per_user: HashMap<i64, usize>,
+}
+
+/// Counts open realtime connections (WebSockets and server-sent event
+/// streams) and turns new ones away once a cap is reached. A cap of 0
+/// means no cap.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    counts: Arc<Mutex<Counts>>,
+    /// Mirrors `counts.total` for whoever is waiting for connections to end
+    open: Arc<watch::Sender<usize>>,
+    max_total: usize,
+    max_per_ip: usize,
+    max_per_user: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            counts: Arc::default(),
+            open: Arc::new(watch::Sender::new(0)),
+            max_total: config.ws_max_connections,
+            max_per_ip: config.ws_max_connections_per_ip,
+            max_per_user: config.ws_max_connections_per_user,
+            metrics,
+        }
+    }
+
+    /// Reserve a slot for a new connection from `ip` by `user_id`, either
+    /// of which may be unknown. The slot is freed when the permit drops.
+    pub fn acquire(
+        &self,
+        ip: Option<String>,
+        user_id: Option<i64>,
+    ) -> Result<ConnectionPermit, LimitExceeded> {
+        let mut counts = self.counts.lock().unwrap();
+
+        let check = || {
+            if at_cap(counts.total, self.max_total) {
+                return Err(LimitExceeded::Global);
+            }
+            if ip
+                .as_ref()
+                .is_some_and(|ip| at_cap(count(&counts.per_ip, ip), self.max_per_ip))
+            {
+                return Err(LimitExceeded::PerIp);
+            }
+            if user_id.is_some_and(|id| at_cap(count(&counts.per_user, &id), self.max_per_user)) {
+                return Err(LimitExceeded::PerUser);
+            }
+            Ok(())
+        };
+        if let Err(limit) = check() {
+            let rejected = match limit {
+                LimitExceeded::Global => &self.metrics.ws_rejected_global_total,
+                LimitExceeded::PerIp => &self.metrics.ws_rejected_per_ip_total,
+                LimitExceeded::PerUser => &self.metrics.ws_rejected_per_user_total,
+            };
+            rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(limit);
+        }
+
+        counts.total += 1;
+        self.open.send_replace(counts.total);
+        if let Some(ref ip) = ip {
+            *counts.per_ip.entry(ip.clone()).or_default() += 1;
+        }
+        if let Some(id) = user_id {
+            *counts.per_user.entry(id).or_default() += 1;
+        }
+        self.metrics.ws_connections.fetch_add(1, Ordering::Relaxed);
+
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            ip,
+            user_id,
+        })
+    }
+
+    /// Resolves once no connections are open
+    pub async fn drained(&self) {
+        let mut open = self.open.subscribe();
+        let _ = open.wait_for(|&n| n == 0).await;
+    }
+}
+
+/// A connection's slot, held for as long as it's open
+pub struct ConnectionPermit {
+    limiter: ConnectionLimiter,
+    ip: Option<String>,
+    user_id: Option<i64>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.total -= 1;
+        self.limiter.open.send_replace(counts.total);
+        if let Some(ref ip) = self.ip {
+            release(&mut counts.per_ip, ip);
+        }
+        if let Some(id) = self.user_id {
+            release(&mut counts.per_user, &id);
+        }
+        self.limiter
+            .metrics
+            .ws_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn at_cap(count: usize, max: usize) -> bool {
+    max != 0 && count >= max
+}
+
+fn count<K: Hash + Eq>(counts: &HashMap<K, usize>, key: &K) -> usize {
+    counts.get(key).copied().unwrap_or(0)
+}
+
+/// Decrement a count, forgetting keys that reach zero
+fn release<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(n) = counts.get_mut(key) {
+        *n -= 1;
+        if *n == 0 {
+            counts.remove(key);
+        }
+    }
+}
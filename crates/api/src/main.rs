@@ -1,5 +1,5 @@
-use api::{config::Config, routes, state::AppState};
-use std::net::SocketAddr;
+use anyhow::Context;
+use api::{config::Config, routes, server, state::AppState, tasks, tls::Certificates};
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -17,6 +17,9 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let config = Config::load()?;
+    let listen_addrs = config.listen_addrs()?;
+    let tls_paths = config.tls_paths()?;
+    let redirect_addrs = config.tls_redirect_addrs()?;
 
     // Initialize database
     tracing::info!("Connecting to database: {}", config.database_url);
@@ -26,17 +29,58 @@ async fn main() -> anyhow::Result<()> {
     db::pool::run_migrations(&pool).await?;
     tracing::info!("Migrations complete");
 
-    let state = AppState::new(config.clone(), pool);
+    let state = AppState::new(config.clone(), pool)?;
+    tasks::spawn_event_relay(state.clone());
+    tasks::spawn_session_sweeper(state.clone());
+    tasks::spawn_event_log_compactor(state.clone());
 
-    let app = routes::router(state)
+    let app = routes::router(state.clone())
         .layer(TraceLayer::new_for_http())
         .layer(CorsLayer::permissive());
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
-    tracing::info!("Listening on {}", addr);
+    let mut listeners = server::bind(&listen_addrs).await?;
+    if let Some((cert_path, key_path)) = tls_paths {
+        let certificates = Certificates::load(cert_path, key_path)?;
+        tasks::spawn_certificate_reloader(certificates.clone(), config.tls_reload_interval());
+        listeners = server::secure(listeners, &certificates)?;
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+        if !redirect_addrs.is_empty() {
+            let https_port = server::https_port(&listeners)
+                .context("HTTPS redirects need a TCP listener to redirect to")?;
+            listeners.extend(server::bind_redirects(&redirect_addrs, https_port).await?);
+        }
+    }
+    server::serve(listeners, app, state, shutdown_signal()).await?;
 
     Ok(())
 }
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
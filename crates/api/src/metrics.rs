@@ -0,0 +1,101 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+};
+
+/// Process-wide counters and gauges, served in the Prometheus text format at
+/// `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Open realtime connections (WebSockets and server-sent event streams)
+    pub ws_connections: AtomicI64,
+    /// Connections turned away by each cap
+    pub ws_rejected_global_total: AtomicU64,
+    pub ws_rejected_per_ip_total: AtomicU64,
+    pub ws_rejected_per_user_total: AtomicU64,
+    /// Frames waiting in realtime connections' outbound queues
+    pub ws_outbound_queue_depth: AtomicI64,
+    /// Frames dropped by the `drop_oldest` overflow policy
+    pub ws_outbound_dropped_total: AtomicU64,
+    /// Frames replaced by a newer update to the same profile
+    pub ws_outbound_coalesced_total: AtomicU64,
+    /// Connections closed because their outbound queue overflowed
+    pub ws_outbound_overflow_disconnects_total: AtomicU64,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        gauge(
+            &mut out,
+            "ws_connections",
+            "Open realtime connections",
+            self.ws_connections.load(Ordering::Relaxed),
+        );
+        labeled_counter(
+            &mut out,
+            "ws_rejected_connections_total",
+            "Realtime connections turned away by a connection cap",
+            "limit",
+            &[
+                ("global", &self.ws_rejected_global_total),
+                ("per_ip", &self.ws_rejected_per_ip_total),
+                ("per_user", &self.ws_rejected_per_user_total),
+            ],
+        );
+        gauge(
+            &mut out,
+            "ws_outbound_queue_depth",
+            "Frames waiting in realtime connections' outbound queues",
+            self.ws_outbound_queue_depth.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "ws_outbound_dropped_total",
+            "Frames dropped because an outbound queue was full",
+            self.ws_outbound_dropped_total.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "ws_outbound_coalesced_total",
+            "Frames replaced by a newer update to the same profile",
+            self.ws_outbound_coalesced_total.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "ws_outbound_overflow_disconnects_total",
+            "Connections closed because their outbound queue overflowed",
+            self.ws_outbound_overflow_disconnects_total
+                .load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    let _ = write!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+    );
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = write!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"
+    );
+}
+
+fn labeled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: &[(&str, &AtomicU64)],
+) {
+    let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} counter\n");
+    for (value, count) in values {
+        let count = count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {count}");
+    }
+}
@@ -1,9 +1,19 @@
-use crate::{error::AppError, state::AppState};
+use crate::{
+    error::AppError,
+    extract::{ClientInfo, CurrentUser},
+    session::{clear_session_cookie, set_session_cookie, SESSION_COOKIE},
+    state::AppState,
+};
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use axum::{extract::State, routing::post, Json, Router};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, get, post},
+    Json, Router,
+};
 use serde::{Deserialize, Serialize};
-use tower_cookies::{Cookie, Cookies};
-use uuid::Uuid;
+use shared::types::{SessionInfo, SessionsRevoked, WsTicket};
+use tower_cookies::Cookies;
 
 #[derive(Deserialize)]
 pub struct RegisterRequest {
@@ -30,11 +40,17 @@ pub fn routes() -> Router<AppState> {
         .route("/api/auth/register", post(register))
         .route("/api/auth/login", post(login))
         .route("/api/auth/logout", post(logout))
+        .route("/api/auth/logout-all", post(logout_all))
+        .route("/api/auth/sessions", get(list_sessions))
+        .route("/api/auth/sessions/{id}", delete(revoke_session))
+        .route("/api/auth/ws-ticket", post(issue_ws_ticket))
+        .route("/api/auth/account", delete(delete_account))
 }
 
 async fn register(
     State(state): State<AppState>,
     cookies: Cookies,
+    client: ClientInfo,
     Json(req): Json<RegisterRequest>,
 ) -> Result<Json<AuthResponse>, AppError> {
     // Validate input
@@ -66,19 +82,8 @@ async fn register(
         .await
         .map_err(|e| AppError::Internal(e.into()))?;
 
-    // Create session
-    let session_id = Uuid::new_v4().to_string();
-    state
-        .sessions
-        .write()
-        .await
-        .insert(session_id.clone(), user_id);
-
-    // Set cookie
-    let mut cookie = Cookie::new("session_id", session_id);
-    cookie.set_path("/");
-    cookie.set_http_only(true);
-    cookies.add(cookie);
+    // Create session and set cookie
+    start_session(&state, &cookies, &client, user_id).await?;
 
     Ok(Json(AuthResponse {
         user_id,
@@ -90,6 +95,7 @@ async fn register(
 async fn login(
     State(state): State<AppState>,
     cookies: Cookies,
+    client: ClientInfo,
     Json(req): Json<LoginRequest>,
 ) -> Result<Json<AuthResponse>, AppError> {
     // Find user
@@ -114,19 +120,8 @@ async fn login(
         .map_err(|e| AppError::Internal(e.into()))?
         .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Profile not found")))?;
 
-    // Create session
-    let session_id = Uuid::new_v4().to_string();
-    state
-        .sessions
-        .write()
-        .await
-        .insert(session_id.clone(), user.id);
-
-    // Set cookie
-    let mut cookie = Cookie::new("session_id", session_id);
-    cookie.set_path("/");
-    cookie.set_http_only(true);
-    cookies.add(cookie);
+    // Create session and set cookie
+    start_session(&state, &cookies, &client, user.id).await?;
 
     Ok(Json(AuthResponse {
         user_id: user.id,
@@ -136,9 +131,134 @@ async fn login(
 }
 
 async fn logout(State(state): State<AppState>, cookies: Cookies) -> Result<(), AppError> {
-    if let Some(cookie) = cookies.get("session_id") {
-        state.sessions.write().await.remove(cookie.value());
-        cookies.remove(Cookie::from("session_id"));
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        state
+            .sessions
+            .delete_session(cookie.value())
+            .await
+            .map_err(|e| AppError::Internal(e.into()))?;
+        clear_session_cookie(&cookies);
+    }
+    Ok(())
+}
+
+/// Log out every session of the current user except this one
+async fn logout_all(
+    State(state): State<AppState>,
+    user: CurrentUser,
+) -> Result<Json<SessionsRevoked>, AppError> {
+    let revoked = state
+        .sessions
+        .revoke_other_sessions(user.id, user.session_id)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    Ok(Json(SessionsRevoked {
+        revoked: revoked as i64,
+    }))
+}
+
+/// Delete the current user's account and profile. Every session is revoked
+/// along with it, so the user's open sockets close with 4001.
+async fn delete_account(
+    State(state): State<AppState>,
+    user: CurrentUser,
+    cookies: Cookies,
+) -> Result<StatusCode, AppError> {
+    state
+        .profile_service
+        .delete_account(user.id)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    clear_session_cookie(&cookies);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_sessions(
+    State(state): State<AppState>,
+    user: CurrentUser,
+) -> Result<Json<Vec<SessionInfo>>, AppError> {
+    let sessions = state
+        .sessions
+        .list_sessions(user.id)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?
+        .into_iter()
+        .map(|s| SessionInfo {
+            current: s.id == user.session_id,
+            id: s.id,
+            user_agent: s.user_agent,
+            ip_address: s.ip_address,
+            created_at: s.created_at,
+            last_seen_at: s.last_seen_at,
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+async fn revoke_session(
+    State(state): State<AppState>,
+    user: CurrentUser,
+    cookies: Cookies,
+    Path(id): Path<i64>,
+) -> Result<Json<SessionsRevoked>, AppError> {
+    let revoked = state
+        .sessions
+        .revoke_session(user.id, id)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    if !revoked {
+        return Err(AppError::NotFound("Session not found".to_string()));
     }
+
+    // Revoking the session we're using is the same as logging out
+    if id == user.session_id {
+        clear_session_cookie(&cookies);
+    }
+
+    Ok(Json(SessionsRevoked { revoked: 1 }))
+}
+
+/// Issue a short-lived ticket for opening `/api/ws?ticket=...` on behalf of
+/// the current session
+async fn issue_ws_ticket(
+    State(state): State<AppState>,
+    user: CurrentUser,
+) -> Result<Json<WsTicket>, AppError> {
+    let ttl = state.config.ws_ticket_ttl();
+    let ticket = state
+        .sessions
+        .issue_ws_ticket(user.session_id, ttl)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    Ok(Json(WsTicket {
+        ticket,
+        expires_in_secs: ttl.as_secs() as i64,
+    }))
+}
+
+/// Create a session for the user and hand its token to the client as a cookie
+async fn start_session(
+    state: &AppState,
+    cookies: &Cookies,
+    client: &ClientInfo,
+    user_id: i64,
+) -> Result<(), AppError> {
+    let session_id = state
+        .sessions
+        .create_session(
+            user_id,
+            client.user_agent.as_deref(),
+            client.ip_address.as_deref(),
+        )
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    set_session_cookie(state, cookies, session_id);
+
     Ok(())
 }
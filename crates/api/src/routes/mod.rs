@@ -1,5 +1,7 @@
 mod auth;
 mod health;
+mod metrics;
+mod presence;
 mod profiles;
 mod ws;
 
@@ -11,7 +13,9 @@ use crate::state::AppState;
 pub fn router(state: AppState) -> Router {
     Router::new()
         .merge(health::routes())
+        .merge(metrics::routes())
         .merge(auth::routes())
+        .merge(presence::routes())
         .merge(profiles::routes())
         .merge(ws::routes())
         .layer(CookieManagerLayer::new())
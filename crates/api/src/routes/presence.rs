@@ -0,0 +1,14 @@
+use crate::{extract::CurrentUser, state::AppState};
+use axum::{extract::State, routing::get, Json, Router};
+use shared::types::OnlineUsers;
+
+pub fn routes() -> Router<AppState> {
+    // Live changes come through the WebSocket `presence` topic
+    Router::new().route("/api/presence", get(online_users))
+}
+
+async fn online_users(State(state): State<AppState>, _user: CurrentUser) -> Json<OnlineUsers> {
+    Json(OnlineUsers {
+        user_ids: state.presence.online(),
+    })
+}
@@ -0,0 +1,99 @@
+use super::{connection::Connection, encoding::Payload, topics::Topic};
+use crate::error::AppError;
+use shared::types::{WsCommand, WsError, WsErrorCode, WsOutcome, WsReply, WsRequest};
+
+/// Parse and run one client request, producing the reply to send back.
+/// Every request gets a reply, including malformed ones.
+pub async fn handle_request(conn: &mut Connection, payload: &Payload) -> WsReply {
+    let value = match payload.decode() {
+        Ok(v) => v,
+        Err(e) => return error_reply(None, AppError::BadRequest(e)),
+    };
+
+    // Read the id on its own first, so even an unknown command can be
+    // correlated by the client
+    let request_id = value
+        .get("request_id")
+        .and_then(|v| v.as_str())
+        .map(str::to_string);
+
+    let request: WsRequest = match serde_json::from_value(value) {
+        Ok(r) => r,
+        Err(e) => {
+            return error_reply(
+                request_id,
+                AppError::BadRequest(format!("Invalid request: {e}")),
+            )
+        }
+    };
+
+    match run_command(conn, request.command).await {
+        Ok(outcome) => WsReply {
+            request_id: Some(request.request_id),
+            outcome,
+        },
+        Err(e) => error_reply(Some(request.request_id), e),
+    }
+}
+
+async fn run_command(conn: &mut Connection, command: WsCommand) -> Result<WsOutcome, AppError> {
+    match command {
+        WsCommand::Subscribe { topics } => {
+            let parsed = parse_topics(conn, &topics)?;
+            conn.subscribe(&parsed).await?;
+            Ok(WsOutcome::Subscribed { topics })
+        }
+        WsCommand::Unsubscribe { topics } => {
+            let parsed = parse_topics(conn, &topics)?;
+            conn.unsubscribe(&parsed);
+            Ok(WsOutcome::Unsubscribed { topics })
+        }
+        WsCommand::UpdateProfile {
+            id,
+            display_name,
+            bio,
+        } => {
+            let user = conn.user().ok_or(AppError::Unauthorized)?;
+            let profile = conn
+                .state()
+                .profile_service
+                .update_own_profile(user.id, id, display_name.as_deref(), bio.as_deref())
+                .await?;
+            Ok(WsOutcome::ProfileUpdated(profile))
+        }
+    }
+}
+
+/// Parse topics and check the connection may subscribe to all of them
+fn parse_topics(conn: &Connection, topics: &[String]) -> Result<Vec<Topic>, AppError> {
+    let parsed = topics
+        .iter()
+        .map(|t| t.parse::<Topic>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(AppError::BadRequest)?;
+
+    if parsed.iter().any(|t| !t.allowed_for(conn.user())) {
+        return Err(AppError::Unauthorized);
+    }
+    Ok(parsed)
+}
+
+/// Same codes and messages as the HTTP error responses
+fn error_reply(request_id: Option<String>, e: AppError) -> WsReply {
+    let (code, message) = match e {
+        AppError::NotFound(msg) => (WsErrorCode::NotFound, msg),
+        AppError::BadRequest(msg) => (WsErrorCode::BadRequest, msg),
+        AppError::Unauthorized => (WsErrorCode::Unauthorized, "Unauthorized".to_string()),
+        AppError::SessionExpired => (WsErrorCode::SessionExpired, "Session expired".to_string()),
+        AppError::TooManyRequests(msg) => (WsErrorCode::TooManyRequests, msg),
+        AppError::Internal(e) => {
+            tracing::error!("Internal error: {:?}", e);
+            (WsErrorCode::Internal, "Internal server error".to_string())
+        }
+    };
+
+    WsReply {
+        request_id,
+        outcome: WsOutcome::Error(WsError { code, message }),
+    }
+}
@@ -0,0 +1,360 @@
+use super::{
+    encoding::{Encoding, Payload},
+    outbound::{Closed, FrameKind, OutboundQueue, Outgoing},
+    topics::Topic,
+    CLOSE_SESSION_REVOKED,
+};
+use crate::{extract::CurrentUser, limits::ConnectionPermit, state::AppState};
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket},
+    response::sse::Event,
+};
+use futures_util::{stream::SplitSink, SinkExt};
+use serde::Serialize;
+use serde_json::json;
+use shared::types::{Profile, SequencedEvent, WsEvent};
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle, time};
+
+/// Where a connection's frames are written
+pub enum Outlet {
+    WebSocket(SplitSink<WebSocket, Message>),
+    /// Server-sent events, for clients that can't open a WebSocket
+    Sse(mpsc::Sender<Event>),
+}
+
+/// The sending half of one realtime connection. Everything written to the
+/// client goes through here, so catch-up, subscription snapshots, live
+/// events and replies can't interleave out of order. Frames are queued and
+/// written by a separate task, so sending never waits on the client.
+pub struct Connection {
+    queue: OutboundQueue,
+    encoding: Encoding,
+    writer: JoinHandle<()>,
+    state: AppState,
+    user: Option<CurrentUser>,
+    topics: HashSet<Topic>,
+    /// Every event up to here has been sent or filtered out
+    cursor: i64,
+}
+
+impl Connection {
+    pub fn new(
+        outlet: Outlet,
+        encoding: Encoding,
+        state: AppState,
+        user: Option<CurrentUser>,
+        topics: Vec<Topic>,
+        permit: ConnectionPermit,
+    ) -> Self {
+        let queue = OutboundQueue::new(
+            state.config.ws_outbound_queue_capacity,
+            state.config.ws_overflow_policy,
+            encoding,
+            state.metrics.clone(),
+        );
+        let write_timeout = state.config.ws_pong_timeout();
+        let writer = tokio::spawn(write_frames(outlet, queue.clone(), write_timeout, permit));
+        Self {
+            queue,
+            encoding,
+            writer,
+            state,
+            user,
+            topics: topics.into_iter().collect(),
+            cursor: 0,
+        }
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    pub fn user(&self) -> Option<&CurrentUser> {
+        self.user.as_ref()
+    }
+
+    pub fn cursor(&self) -> i64 {
+        self.cursor
+    }
+
+    /// Bring the client up to date from `since` on its current topics.
+    /// Replays the missed events if the log still has them all, and sends a
+    /// snapshot of every topic otherwise.
+    pub async fn catch_up(&mut self, since: Option<i64>) -> anyhow::Result<()> {
+        if let Some(since) = since {
+            if let Some(events) = self.state.events.replay_since(since).await? {
+                self.cursor = since;
+                for event in events {
+                    self.forward(event)?;
+                }
+                return Ok(());
+            }
+        }
+
+        // The head is read before the snapshot, so anything newer is re-sent
+        // as a live event rather than lost
+        self.cursor = self.state.events.head().await?;
+        let topics: Vec<Topic> = self.topics.iter().copied().collect();
+        self.send_snapshot(&topics).await
+    }
+
+    /// Send a live event if it's new and matches a subscription. Closes the
+    /// connection if the event revokes the session it was opened with.
+    pub fn forward(&mut self, event: SequencedEvent) -> Result<(), Closed> {
+        if event.seq <= self.cursor {
+            return Ok(());
+        }
+        self.cursor = event.seq;
+
+        if let WsEvent::SessionRevoked { session_id } = event.event {
+            if self
+                .user
+                .as_ref()
+                .is_some_and(|u| u.session_id == session_id)
+            {
+                self.close(CLOSE_SESSION_REVOKED, "Session revoked");
+                return Err(Closed);
+            }
+        }
+
+        if self.topics.iter().any(|t| t.matches(&event.event)) {
+            self.send_event(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Send a presence change if the socket is subscribed to presence.
+    /// Presence isn't part of the event log, so it's tagged with the cursor.
+    pub fn forward_presence(&mut self, event: WsEvent) -> Result<(), Closed> {
+        if !self.topics.contains(&Topic::Presence) {
+            return Ok(());
+        }
+        let event = SequencedEvent {
+            seq: self.cursor,
+            event,
+        };
+        self.send_event(&event)
+    }
+
+    /// Send who is online as one frame, when presence is subscribed to or
+    /// after missing changes to it
+    pub fn resync_presence(&mut self) -> Result<(), Closed> {
+        let user_ids = self.state.presence.online();
+        self.forward_presence(WsEvent::PresenceSnapshot { user_ids })
+    }
+
+    /// Add topics and send a snapshot of the ones that are new
+    pub async fn subscribe(&mut self, topics: &[Topic]) -> anyhow::Result<()> {
+        // Bring the existing topics up to date first, so the new topics'
+        // snapshot is consistent with the cursor it's tagged with
+        self.catch_up(Some(self.cursor)).await?;
+
+        let new: Vec<Topic> = topics
+            .iter()
+            .copied()
+            .filter(|t| self.topics.insert(*t))
+            .collect();
+        self.send_snapshot(&new).await
+    }
+
+    pub fn unsubscribe(&mut self, topics: &[Topic]) {
+        for topic in topics {
+            self.topics.remove(topic);
+        }
+    }
+
+    /// Send the current state of `topics`, tagged with the cursor: every
+    /// profile they cover in one `Snapshot` frame, then who is online if
+    /// presence is among them
+    async fn send_snapshot(&mut self, topics: &[Topic]) -> anyhow::Result<()> {
+        if topics.iter().any(|t| *t != Topic::Presence) {
+            let profiles = self.load_profiles(topics).await?;
+            let event = SequencedEvent {
+                seq: self.cursor,
+                event: WsEvent::Snapshot {
+                    profiles,
+                    cursor: self.cursor,
+                },
+            };
+            self.send_event(&event)?;
+        }
+
+        if topics.contains(&Topic::Presence) {
+            self.resync_presence()?;
+        }
+        Ok(())
+    }
+
+    /// Every profile covered by `topics`, without duplicates
+    async fn load_profiles(&self, topics: &[Topic]) -> Result<Vec<Profile>, sqlx::Error> {
+        let service = &self.state.profile_service;
+        if topics.contains(&Topic::AllProfiles) {
+            return service.list_profiles().await;
+        }
+
+        let mut profiles = BTreeMap::new();
+        for topic in topics {
+            let profile = match topic {
+                Topic::Profile(id) => service.get_profile_by_id(*id).await?,
+                Topic::User(id) => service.get_profile_by_user_id(*id).await?,
+                Topic::AllProfiles | Topic::Presence => None,
+            };
+            if let Some(p) = profile {
+                profiles.insert(p.id, p);
+            }
+        }
+        Ok(profiles.into_values().collect())
+    }
+
+    /// Send an event frame. Over SSE the sequence number is also the event
+    /// id, so the browser resumes from it with `Last-Event-ID`.
+    fn send_event(&mut self, event: &SequencedEvent) -> Result<(), Closed> {
+        let kind = match &event.event {
+            WsEvent::Snapshot { .. } => FrameKind::Snapshot,
+            WsEvent::Profile(_) | WsEvent::ProfilePatch(_) | WsEvent::Deleted { .. } => {
+                FrameKind::Profile(event.clone())
+            }
+            _ => FrameKind::Event,
+        };
+        self.send_encoded(event, Some(event.seq), kind)
+    }
+
+    /// Send a reply in the connection's encoding. Frames that fail to
+    /// serialize are logged and skipped.
+    pub fn send<T: Serialize>(&mut self, frame: &T) -> Result<(), Closed> {
+        self.send_encoded(frame, None, FrameKind::Reply)
+    }
+
+    fn send_encoded<T: Serialize>(
+        &mut self,
+        frame: &T,
+        id: Option<i64>,
+        kind: FrameKind,
+    ) -> Result<(), Closed> {
+        let payload = match self.encoding.encode(frame) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::error!("Failed to serialize event: {}", e);
+                return Ok(());
+            }
+        };
+        self.queue.push(Outgoing::Data { payload, id, kind })
+    }
+
+    /// Ping a WebSocket. SSE streams are kept alive by comment lines instead.
+    pub fn ping(&mut self) -> Result<(), Closed> {
+        self.queue.push_ping()
+    }
+
+    /// Tell the client why the connection is ending, after anything already
+    /// queued
+    pub fn close(&mut self, code: u16, reason: &'static str) {
+        self.queue.close(code, reason);
+    }
+
+    /// Resolves when the writer has stopped: the client went away, or the
+    /// connection was closed and everything queued has been written
+    pub async fn closed(&mut self) {
+        let _ = (&mut self.writer).await;
+    }
+}
+
+impl Drop for Connection {
+    // Let the writer flush what's queued (usually a close frame) and stop
+    fn drop(&mut self) {
+        self.queue.finish();
+    }
+}
+
+/// Write queued frames to the client until the queue is drained and closed,
+/// or the client goes away. The connection counts against the caps (and
+/// holds up shutdown) until then, so a client that stops reading gets
+/// `write_timeout` per frame, as long as it gets to answer a ping.
+async fn write_frames(
+    mut outlet: Outlet,
+    queue: OutboundQueue,
+    write_timeout: Duration,
+    _permit: ConnectionPermit,
+) {
+    loop {
+        let frame = match &outlet {
+            // Notice a dropped SSE stream even while there's nothing to send
+            Outlet::Sse(tx) => tokio::select! {
+                frame = queue.pop() => frame,
+                () = tx.closed() => None,
+            },
+            Outlet::WebSocket(_) => queue.pop().await,
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+
+        let last = matches!(frame, Outgoing::Close { .. });
+        let Ok(written) = time::timeout(write_timeout, write_frame(&mut outlet, frame)).await
+        else {
+            tracing::debug!("Client stopped reading, dropping the connection");
+            break;
+        };
+        if written.is_err() || last {
+            break;
+        }
+    }
+
+    // Make further sends fail rather than pile up
+    queue.finish();
+}
+
+async fn write_frame(outlet: &mut Outlet, frame: Outgoing) -> Result<(), axum::Error> {
+    match outlet {
+        Outlet::WebSocket(sender) => {
+            let message = match frame {
+                Outgoing::Data {
+                    payload: Payload::Text(text),
+                    ..
+                } => Message::Text(text.into()),
+                Outgoing::Data {
+                    payload: Payload::Binary(bytes),
+                    ..
+                } => Message::Binary(bytes.into()),
+                Outgoing::Ping => Message::Ping(Default::default()),
+                Outgoing::Close { code, reason } => Message::Close(Some(CloseFrame {
+                    code,
+                    reason: reason.into(),
+                })),
+            };
+            sender.send(message).await
+        }
+        Outlet::Sse(tx) => {
+            let event = match frame {
+                Outgoing::Data {
+                    payload: Payload::Text(text),
+                    id,
+                    ..
+                } => {
+                    let event = Event::default().data(text);
+                    match id {
+                        Some(id) => event.id(id.to_string()),
+                        None => event,
+                    }
+                }
+                // SSE streams are always JSON
+                Outgoing::Data {
+                    payload: Payload::Binary(_),
+                    ..
+                } => return Err(axum::Error::new("binary frame on an SSE stream")),
+                Outgoing::Ping => return Ok(()),
+                // SSE has no close frames, so the code and reason are sent
+                // as a `close` event
+                Outgoing::Close { code, reason } => {
+                    let data = json!({ "code": code, "reason": reason });
+                    Event::default().event("close").data(data.to_string())
+                }
+            };
+            tx.send(event).await.map_err(axum::Error::new)
+        }
+    }
+}
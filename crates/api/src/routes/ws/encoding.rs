@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+
+/// How a connection's frames are serialized. JSON goes in text frames and
+/// MessagePack in binary frames; the types are the same either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+/// An encoded frame
+pub enum Payload {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Encoding {
+    /// WebSocket subprotocols the server can negotiate
+    pub const SUBPROTOCOLS: [&'static str; 2] = ["json", "msgpack"];
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::Msgpack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, frame: &T) -> Result<Payload, String> {
+        match self {
+            Encoding::Json => serde_json::to_string(frame)
+                .map(Payload::Text)
+                .map_err(|e| e.to_string()),
+            // Named fields, so the MessagePack maps mirror the JSON objects
+            Encoding::Msgpack => rmp_serde::to_vec_named(frame)
+                .map(Payload::Binary)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl Payload {
+    /// Decode a frame from the client, in whichever encoding it was sent
+    pub fn decode(&self) -> Result<serde_json::Value, String> {
+        match self {
+            Payload::Text(text) => {
+                serde_json::from_str(text).map_err(|e| format!("Invalid JSON: {e}"))
+            }
+            Payload::Binary(bytes) => {
+                rmp_serde::from_slice(bytes).map_err(|e| format!("Invalid MessagePack: {e}"))
+            }
+        }
+    }
+}
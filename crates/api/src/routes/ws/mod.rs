@@ -0,0 +1,427 @@
+mod commands;
+mod connection;
+mod encoding;
+mod outbound;
+mod sse;
+mod topics;
+
+use crate::{
+    error::AppError,
+    extract::{ClientInfo, CurrentUser, OptionalUser},
+    limits::ConnectionPermit,
+    state::AppState,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Query, State, WebSocketUpgrade,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use connection::{Connection, Outlet};
+use domain::{PresenceRegistry, SessionStatus};
+use encoding::{Encoding, Payload};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time::{self, Instant},
+};
+use topics::Topic;
+
+/// Close code sent when the session the socket was opened with is revoked
+/// (logout, "log out other devices", or expiry).
+pub const CLOSE_SESSION_REVOKED: u16 = 4001;
+
+/// Close code sent when the socket fell behind the event stream and the
+/// server couldn't catch it up again. Clients should reconnect.
+pub const CLOSE_RESYNC_FAILED: u16 = 4002;
+
+/// Close code sent when the client didn't answer a ping in time
+pub const CLOSE_HEARTBEAT_TIMEOUT: u16 = 4003;
+
+/// Close code sent when the socket reached its maximum lifetime. Clients
+/// should reconnect and resume with `?since=`.
+pub const CLOSE_LIFETIME_EXCEEDED: u16 = 4004;
+
+/// Close code sent when the client read too slowly and its outbound queue
+/// overflowed. Clients should reconnect and resume with `?since=`.
+pub const CLOSE_QUEUE_FULL: u16 = 4005;
+
+/// Close code sent when the server is shutting down (the standard "Service
+/// Restart"). Clients should reconnect after a short delay and resume with
+/// `?since=`.
+pub const CLOSE_SERVICE_RESTART: u16 = 1012;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/ws", get(ws_handler))
+        .route("/api/events", get(sse::sse_handler))
+}
+
+/// Query parameters of `/api/ws` and `/api/events`
+#[derive(Deserialize)]
+struct WsParams {
+    /// Single-use ticket from `POST /api/auth/ws-ticket`, for clients that
+    /// can't send the session cookie
+    ticket: Option<String>,
+    /// Last sequence number the client saw, to resume after a reconnect
+    since: Option<i64>,
+    /// Initial subscriptions, comma-separated (e.g. `profiles:*,user:1`)
+    topics: Option<String>,
+    /// Frame encoding, for WebSocket clients that can't pick a subprotocol
+    encoding: Option<Encoding>,
+}
+
+async fn ws_handler(
+    State(state): State<AppState>,
+    Query(params): Query<WsParams>,
+    OptionalUser(cookie_user): OptionalUser,
+    client: ClientInfo,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let (user, topics) = authorize(&state, &params, cookie_user).await?;
+    let permit = state
+        .connections
+        .acquire(client.ip_address, user.as_ref().map(|u| u.id))?;
+    redeem_ticket(&state, &params).await?;
+
+    // A negotiated subprotocol wins over `?encoding=`. Extensions such as
+    // permessage-deflate are never negotiated; tungstenite has none.
+    let ws = ws.protocols(Encoding::SUBPROTOCOLS);
+    let encoding = ws
+        .selected_protocol()
+        .and_then(|p| p.to_str().ok())
+        .and_then(Encoding::from_subprotocol)
+        .or(params.encoding)
+        .unwrap_or_default();
+
+    Ok(ws.on_upgrade(move |socket| {
+        handle_socket(socket, state, user, topics, params.since, encoding, permit)
+    }))
+}
+
+/// Work out who is connecting and check they may open the connection with
+/// the topics they asked for. A ticket isn't used up yet, so a connection
+/// turned away after this (e.g. over a cap) can retry with it; call
+/// [`redeem_ticket`] once the connection is sure to go ahead.
+async fn authorize(
+    state: &AppState,
+    params: &WsParams,
+    cookie_user: Option<CurrentUser>,
+) -> Result<(Option<CurrentUser>, Vec<Topic>), AppError> {
+    let user = match params.ticket {
+        Some(ref ticket) => Some(ticket_user(state, ticket).await?),
+        None => cookie_user,
+    };
+
+    // Anonymous connections only get in when the public feed is enabled
+    if user.is_none() && !state.config.ws_public_feed {
+        return Err(AppError::Unauthorized);
+    }
+
+    let topics = Topic::parse_list(params.topics.as_deref().unwrap_or_default())
+        .map_err(AppError::BadRequest)?;
+    if topics.iter().any(|t| !t.allowed_for(user.as_ref())) {
+        return Err(AppError::Unauthorized);
+    }
+
+    Ok((user, topics))
+}
+
+async fn ticket_user(state: &AppState, ticket: &str) -> Result<CurrentUser, AppError> {
+    let status = state
+        .sessions
+        .check_ws_ticket(ticket)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    match status {
+        SessionStatus::Active(session) => CurrentUser::from_session(state, session).await,
+        SessionStatus::Expired => Err(AppError::SessionExpired),
+        SessionStatus::Missing => Err(AppError::Unauthorized),
+    }
+}
+
+/// Use up the connection's ticket, if it came with one. Another connection
+/// may have redeemed it since [`authorize`], in which case this one
+/// doesn't get in.
+async fn redeem_ticket(state: &AppState, params: &WsParams) -> Result<(), AppError> {
+    let Some(ref ticket) = params.ticket else {
+        return Ok(());
+    };
+    let redeemed = state
+        .sessions
+        .redeem_ws_ticket(ticket)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+    if !redeemed {
+        return Err(AppError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// What the recv task hands to the send task
+enum Inbound {
+    /// A data frame, to be handled as a `WsRequest`
+    Request(Payload),
+    /// Any other frame (usually a pong); proves the peer is still there
+    Alive,
+}
+
+/// Runs one WebSocket connection. `user` is the authenticated user, or
+/// `None` for an anonymous socket on the public feed. `topics` are the
+/// initial subscriptions and `since` is the last sequence number a
+/// reconnecting client saw. Frames are sent in `encoding`; requests are
+/// accepted in either encoding. `permit` holds the socket's place under the
+/// connection caps.
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    user: Option<CurrentUser>,
+    topics: Vec<Topic>,
+    since: Option<i64>,
+    encoding: Encoding,
+    permit: ConnectionPermit,
+) {
+    match user {
+        Some(ref u) => tracing::debug!("WebSocket opened by user {}", u.id),
+        None => tracing::debug!("WebSocket opened anonymously"),
+    }
+
+    let (sender, mut receiver) = socket.split();
+
+    // Client requests are handled by the send task, in order with events
+    let (inbound_tx, inbound_rx) = mpsc::channel::<Inbound>(32);
+
+    let pong_timeout = state.config.ws_pong_timeout();
+    let conn = Connection::new(
+        Outlet::WebSocket(sender),
+        encoding,
+        state,
+        user,
+        topics,
+        permit,
+    );
+    let mut send_task = tokio::spawn(run_connection(conn, since, Some(inbound_rx)));
+
+    // Handle incoming messages: text frames are `WsRequest`s, and every
+    // frame counts as a sign of life
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(msg) = receiver.next().await {
+            let inbound = match msg {
+                Ok(Message::Text(text)) => Inbound::Request(Payload::Text(text.to_string())),
+                Ok(Message::Binary(bytes)) => Inbound::Request(Payload::Binary(bytes.to_vec())),
+                Ok(Message::Close(_)) => break,
+                Ok(Message::Ping(data)) => {
+                    // Ping is handled automatically by axum
+                    tracing::debug!("Received ping: {:?}", data);
+                    Inbound::Alive
+                }
+                Ok(_) => Inbound::Alive,
+                Err(e) => {
+                    tracing::error!("WebSocket error: {}", e);
+                    break;
+                }
+            };
+
+            // Stop once the send task is gone
+            if inbound_tx.send(inbound).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Wait for either task to complete, then stop the other. When the server
+    // ends the connection, the client gets as long to answer its close frame
+    // as it would a ping, so the close handshake can finish.
+    tokio::select! {
+        _ = &mut send_task => {
+            if time::timeout(pong_timeout, &mut recv_task).await.is_err() {
+                recv_task.abort();
+            }
+        }
+        _ = &mut recv_task => send_task.abort(),
+    }
+
+    tracing::debug!("WebSocket connection closed");
+}
+
+/// Drive the sending side of a connection until the client goes away or
+/// the connection has to be closed:
+/// 1. Catch up: replay what a reconnecting client missed, or send the
+///    current state of every topic if it's new or the log no longer covers
+///    the gap
+/// 2. Forward future updates and answer requests from `inbound` (WebSocket
+///    only). WebSockets are also pinged, and closed if a ping goes
+///    unanswered.
+///
+/// Either way the connection is closed once it's been open too long, or
+/// when the server shuts down.
+async fn run_connection(
+    mut conn: Connection,
+    since: Option<i64>,
+    mut inbound: Option<mpsc::Receiver<Inbound>>,
+) {
+    let state = conn.state().clone();
+    // Remember which session opened the connection so revoking it can close it
+    let session_id = conn.user().map(|u| u.session_id);
+
+    // Signed-in users are online until the connection ends
+    let _online = conn.user().map(|u| Online::new(&state.presence, u.id));
+
+    // Subscribe to events BEFORE reading the log to avoid race conditions
+    let mut events_rx = state.subscribe_events();
+    let mut presence_rx = state.presence.subscribe();
+
+    if conn.catch_up(since).await.is_err() {
+        tracing::debug!("Connection closed during initial state");
+        return;
+    }
+
+    let heartbeat = inbound.is_some();
+    let ping_every = state.config.ws_ping_interval();
+    let mut ping_interval = time::interval_at(Instant::now() + ping_every, ping_every);
+    // Set while a ping is unanswered
+    let mut pong_deadline: Option<Instant> = None;
+    let lifetime = time::sleep(state.config.ws_max_lifetime());
+    tokio::pin!(lifetime);
+
+    loop {
+        tokio::select! {
+            Some(inbound) = recv_inbound(&mut inbound) => {
+                pong_deadline = None;
+                if let Inbound::Request(payload) = inbound {
+                    // The revocation may still be on its way, so don't act
+                    // for a session that has already ended
+                    if !session_is_live(&state, session_id).await {
+                        conn.close(CLOSE_SESSION_REVOKED, "Session revoked");
+                        break;
+                    }
+                    let reply = commands::handle_request(&mut conn, &payload).await;
+                    if conn.send(&reply).is_err() {
+                        break;
+                    }
+                }
+            }
+            _ = ping_interval.tick(), if heartbeat => {
+                if conn.ping().is_err() {
+                    break;
+                }
+                pong_deadline.get_or_insert(Instant::now() + state.config.ws_pong_timeout());
+            }
+            _ = heartbeat_expired(pong_deadline) => {
+                tracing::debug!("WebSocket missed its heartbeat");
+                conn.close(CLOSE_HEARTBEAT_TIMEOUT, "Heartbeat timeout");
+                break;
+            }
+            () = &mut lifetime => {
+                conn.close(CLOSE_LIFETIME_EXCEEDED, "Connection lifetime exceeded");
+                break;
+            }
+            () = state.shutdown.wait() => {
+                conn.close(CLOSE_SERVICE_RESTART, "Server restarting");
+                break;
+            }
+            () = conn.closed() => break,
+            event = events_rx.recv() => match event {
+                // Some events before this one never reached us, e.g. ones
+                // another instance's relay kept to its own connections:
+                // fill in from the log, which has this one too
+                Ok(published) if published.after > conn.cursor() => {
+                    let cursor = conn.cursor();
+                    if conn.catch_up(Some(cursor)).await.is_err() {
+                        conn.close(CLOSE_RESYNC_FAILED, "Resync failed");
+                        break;
+                    }
+                }
+                Ok(published) => {
+                    if conn.forward(published.event).is_err() {
+                        break;
+                    }
+                }
+                // We missed events: catch up from the log again, or close
+                // if we can't. The log may no longer have our session's
+                // revocation, so ask the store about it first.
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Connection lagged by {} events, resyncing", skipped);
+                    if !session_is_live(&state, session_id).await {
+                        conn.close(CLOSE_SESSION_REVOKED, "Session revoked");
+                        break;
+                    }
+                    let cursor = conn.cursor();
+                    if conn.catch_up(Some(cursor)).await.is_err() {
+                        conn.close(CLOSE_RESYNC_FAILED, "Resync failed");
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            },
+            event = presence_rx.recv() => match event {
+                Ok(event) => {
+                    if conn.forward_presence(event).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {
+                    if conn.resync_presence().is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+/// Whether the session a connection was opened with is still live;
+/// anonymous connections have none to lose. A database error gives it
+/// the benefit of the doubt.
+async fn session_is_live(state: &AppState, session_id: Option<i64>) -> bool {
+    match session_id {
+        Some(id) => state.sessions.session_is_live(id).await.unwrap_or(true),
+        None => true,
+    }
+}
+
+/// Next message from the recv task; never resolves for connections that
+/// don't have one
+async fn recv_inbound(inbound: &mut Option<mpsc::Receiver<Inbound>>) -> Option<Inbound> {
+    match inbound {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once an unanswered ping's deadline has passed
+async fn heartbeat_expired(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Keeps a user marked online while it's alive, including when the task
+/// holding it is aborted
+struct Online {
+    presence: PresenceRegistry,
+    user_id: i64,
+}
+
+impl Online {
+    fn new(presence: &PresenceRegistry, user_id: i64) -> Self {
+        presence.join(user_id);
+        Self {
+            presence: presence.clone(),
+            user_id,
+        }
+    }
+}
+
+impl Drop for Online {
+    fn drop(&mut self) {
+        self.presence.leave(self.user_id);
+    }
+}
@@ -0,0 +1,314 @@
+use super::{
+    encoding::{Encoding, Payload},
+    CLOSE_QUEUE_FULL,
+};
+use crate::{config::OverflowPolicy, metrics::Metrics};
+use shared::types::{SequencedEvent, WsEvent};
+use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+/// A frame waiting to be written to the client
+pub enum Outgoing {
+    /// An encoded frame. `id` becomes the SSE event id.
+    Data {
+        payload: Payload,
+        id: Option<i64>,
+        kind: FrameKind,
+    },
+    Ping,
+    Close {
+        code: u16,
+        reason: &'static str,
+    },
+}
+
+/// What a data frame carries, as far as the overflow policies care
+pub enum FrameKind {
+    /// A `Snapshot`, which is never dropped: the client can't tell what it
+    /// was missing
+    Snapshot,
+    /// An event about one profile, which a later one about the same
+    /// profile may be merged into
+    Profile(SequencedEvent),
+    /// Any other event
+    Event,
+    /// The answer to a client's request, which is never dropped: the
+    /// client is waiting for it
+    Reply,
+}
+
+/// The connection won't take any more frames: the client went away, the
+/// connection is closing, or the client fell too far behind.
+#[derive(Debug, thiserror::Error)]
+#[error("connection closed")]
+pub struct Closed;
+
+/// Bounded queue between a connection's event loop and the task writing to
+/// the client, so a slow client never holds up the broadcast receivers.
+/// When it's full, the configured `OverflowPolicy` decides what gives.
+#[derive(Clone)]
+pub struct OutboundQueue {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// For re-encoding merged frames
+    encoding: Encoding,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    frames: VecDeque<Outgoing>,
+    /// No more frames are accepted; the writer drains the rest and stops
+    closed: bool,
+}
+
+impl OutboundQueue {
+    pub fn new(
+        capacity: usize,
+        policy: OverflowPolicy,
+        encoding: Encoding,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::default(),
+                notify: Notify::new(),
+                capacity,
+                policy,
+                encoding,
+                metrics,
+            }),
+        }
+    }
+
+    /// Queue a frame, applying the overflow policy if the queue is full
+    pub fn push(&self, mut frame: Outgoing) -> Result<(), Closed> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+
+        if state.frames.len() >= shared.capacity {
+            match shared.policy {
+                OverflowPolicy::DropOldest => match droppable(&state.frames) {
+                    Some(i) => {
+                        state.frames.remove(i);
+                        shared.dequeued(1);
+                        shared
+                            .metrics
+                            .ws_outbound_dropped_total
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    None => return Err(shared.overflow(&mut state)),
+                },
+                // Fold the queued update into the new one, which goes to the
+                // back so frames stay in sequence order
+                OverflowPolicy::Coalesce => match shared.coalesce(&mut state.frames, &frame) {
+                    Some(merged) => {
+                        frame = merged;
+                        shared.dequeued(1);
+                        shared
+                            .metrics
+                            .ws_outbound_coalesced_total
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    None => return Err(shared.overflow(&mut state)),
+                },
+                OverflowPolicy::Disconnect => return Err(shared.overflow(&mut state)),
+            }
+        }
+
+        state.frames.push_back(frame);
+        shared.enqueued();
+        Ok(())
+    }
+
+    /// Queue a ping ahead of everything else. Pings aren't subject to the
+    /// capacity, so a slow but live client isn't taken for a dead one.
+    pub fn push_ping(&self) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+        state.frames.push_front(Outgoing::Ping);
+        self.shared.enqueued();
+        Ok(())
+    }
+
+    /// Queue a close frame after whatever is already queued, and accept
+    /// nothing more
+    pub fn close(&self, code: u16, reason: &'static str) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        state.frames.push_back(Outgoing::Close { code, reason });
+        state.closed = true;
+        self.shared.enqueued();
+    }
+
+    /// Accept nothing more; the writer stops once the queue is drained
+    pub fn finish(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+    }
+
+    /// Next frame to write, or `None` once the queue is closed and drained
+    pub async fn pop(&self) -> Option<Outgoing> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(frame) = state.frames.pop_front() {
+                    self.shared.dequeued(1);
+                    return Some(frame);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+impl Shared {
+    fn enqueued(&self) {
+        self.metrics
+            .ws_outbound_queue_depth
+            .fetch_add(1, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    fn dequeued(&self, n: usize) {
+        self.metrics
+            .ws_outbound_queue_depth
+            .fetch_sub(n as i64, Ordering::Relaxed);
+    }
+
+    /// Take the last queued update to the same profile as `frame` out of
+    /// `frames`, returning the two merged into one frame. `None` if there's
+    /// no such update or the two can't be merged.
+    fn coalesce(&self, frames: &mut VecDeque<Outgoing>, frame: &Outgoing) -> Option<Outgoing> {
+        let Outgoing::Data {
+            kind: FrameKind::Profile(later),
+            ..
+        } = frame
+        else {
+            return None;
+        };
+        // The latest, so the merged frame still follows any earlier ones
+        let (i, earlier) =
+            frames
+                .iter()
+                .enumerate()
+                .rev()
+                .find_map(|(i, queued)| match queued {
+                    Outgoing::Data {
+                        kind: FrameKind::Profile(earlier),
+                        ..
+                    } if profile_id(&earlier.event) == profile_id(&later.event) => {
+                        Some((i, earlier))
+                    }
+                    _ => None,
+                })?;
+
+        let merged = merge(earlier, later)?;
+        let payload = self
+            .encoding
+            .encode(&merged)
+            .inspect_err(|e| tracing::error!("Failed to serialize merged event: {}", e))
+            .ok()?;
+        frames.remove(i);
+        Some(Outgoing::Data {
+            payload,
+            id: Some(merged.seq),
+            kind: FrameKind::Profile(merged),
+        })
+    }
+
+    /// Give up on a client that can't keep up: discard what's queued and
+    /// close the connection
+    fn overflow(&self, state: &mut QueueState) -> Closed {
+        tracing::warn!("Outbound queue full, disconnecting slow client");
+        self.dequeued(state.frames.len());
+        state.frames.clear();
+        state.frames.push_back(Outgoing::Close {
+            code: CLOSE_QUEUE_FULL,
+            reason: "Client too slow",
+        });
+        state.closed = true;
+        self.enqueued();
+        self.metrics
+            .ws_outbound_overflow_disconnects_total
+            .fetch_add(1, Ordering::Relaxed);
+        Closed
+    }
+}
+
+impl Drop for Shared {
+    // Frames left behind when the writer gave up no longer count as queued
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        self.metrics
+            .ws_outbound_queue_depth
+            .fetch_sub(state.frames.len() as i64, Ordering::Relaxed);
+    }
+}
+
+/// Position of the oldest frame `DropOldest` may drop: any event but a
+/// snapshot
+fn droppable(frames: &VecDeque<Outgoing>) -> Option<usize> {
+    frames.iter().position(|frame| {
+        matches!(
+            frame,
+            Outgoing::Data {
+                kind: FrameKind::Profile(_) | FrameKind::Event,
+                ..
+            }
+        )
+    })
+}
+
+fn profile_id(event: &WsEvent) -> Option<i64> {
+    match event {
+        WsEvent::Profile(p) => Some(p.id),
+        WsEvent::ProfilePatch(p) => Some(p.id),
+        WsEvent::Deleted { id, .. } => Some(*id),
+        _ => None,
+    }
+}
+
+/// One event with the effect of `earlier` followed by `later`, both about
+/// the same profile, sent where `later` is. Patches merge into a patch from
+/// the earlier one's base version, a patch to a full profile is applied to
+/// it, and a full profile or deletion supersedes whatever came before.
+fn merge(earlier: &SequencedEvent, later: &SequencedEvent) -> Option<SequencedEvent> {
+    let event = match (&earlier.event, &later.event) {
+        (_, WsEvent::Profile(_) | WsEvent::Deleted { .. }) => later.event.clone(),
+        (WsEvent::ProfilePatch(earlier), WsEvent::ProfilePatch(later)) => {
+            let mut patch = earlier.clone();
+            patch.merge(later.clone());
+            WsEvent::ProfilePatch(patch)
+        }
+        (WsEvent::Profile(profile), WsEvent::ProfilePatch(patch)) => {
+            let mut profile = profile.clone();
+            profile.apply(patch.clone());
+            WsEvent::Profile(profile)
+        }
+        _ => return None,
+    };
+    Some(SequencedEvent {
+        seq: later.seq,
+        event,
+    })
+}
@@ -0,0 +1,60 @@
+use super::{authorize, redeem_ticket, run_connection, Connection, Encoding, Outlet, WsParams};
+use crate::{
+    error::AppError,
+    extract::{ClientInfo, OptionalUser},
+    state::AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream};
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+
+/// `GET /api/events`: the WebSocket feed as server-sent events, for clients
+/// behind proxies that break WebSocket upgrades. Authentication, topics and
+/// frames are the same as `/api/ws`; each event's id is its sequence
+/// number, so browsers resume with `Last-Event-ID` on their own.
+pub(super) async fn sse_handler(
+    State(state): State<AppState>,
+    Query(params): Query<WsParams>,
+    OptionalUser(cookie_user): OptionalUser,
+    client: ClientInfo,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    if params.encoding.is_some_and(|e| e != Encoding::Json) {
+        return Err(AppError::BadRequest(
+            "Server-sent events are always JSON".to_string(),
+        ));
+    }
+    let (user, topics) = authorize(&state, &params, cookie_user).await?;
+
+    let since = match headers.get("last-event-id") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| AppError::BadRequest("Invalid Last-Event-ID".to_string()))?,
+        ),
+        None => params.since,
+    };
+
+    let permit = state
+        .connections
+        .acquire(client.ip_address, user.as_ref().map(|u| u.id))?;
+    redeem_ticket(&state, &params).await?;
+
+    let (tx, rx) = mpsc::channel(state.config.event_channel_capacity);
+    let keep_alive = KeepAlive::new().interval(state.config.ws_ping_interval());
+    let conn = Connection::new(Outlet::Sse(tx), Encoding::Json, state, user, topics, permit);
+    tokio::spawn(run_connection(conn, since, None));
+
+    let events = stream::unfold(rx, |mut rx| async move {
+        let event = rx.recv().await?;
+        Some((Ok(event), rx))
+    });
+    Ok(Sse::new(events).keep_alive(keep_alive))
+}
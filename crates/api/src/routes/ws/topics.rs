@@ -0,0 +1,94 @@
+use crate::extract::CurrentUser;
+use shared::types::WsEvent;
+use std::{fmt, str::FromStr};
+
+/// Something a socket can subscribe to. Written on the wire as
+/// `profiles:*`, `profile:{id}`, `user:{id}` or `presence`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    /// Every profile
+    AllProfiles,
+    /// One profile, by profile id
+    Profile(i64),
+    /// Everything about one user; only that user may subscribe
+    User(i64),
+    /// Users coming online and going offline; signed-in sockets only
+    Presence,
+}
+
+impl Topic {
+    /// Parse a comma-separated list, as in `?topics=profiles:*,user:1`
+    pub fn parse_list(s: &str) -> Result<Vec<Topic>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    pub fn matches(&self, event: &WsEvent) -> bool {
+        match (self, event) {
+            (Topic::AllProfiles, WsEvent::Profile(_)) => true,
+            (Topic::Profile(id), WsEvent::Profile(p)) => p.id == *id,
+            (Topic::User(id), WsEvent::Profile(p)) => p.user_id == *id,
+            (Topic::AllProfiles, WsEvent::ProfilePatch(_)) => true,
+            (Topic::Profile(id), WsEvent::ProfilePatch(p)) => p.id == *id,
+            (Topic::User(id), WsEvent::ProfilePatch(p)) => p.user_id == *id,
+            (Topic::AllProfiles, WsEvent::Deleted { .. } | WsEvent::Removed { .. }) => true,
+            (Topic::Profile(id), WsEvent::Deleted { id: deleted, .. }) => deleted == id,
+            (Topic::User(id), WsEvent::Deleted { user_id, .. } | WsEvent::Removed { user_id }) => {
+                user_id == id
+            }
+            (
+                Topic::Presence,
+                WsEvent::PresenceJoined { .. }
+                | WsEvent::PresenceLeft { .. }
+                | WsEvent::PresenceSnapshot { .. },
+            ) => true,
+            _ => false,
+        }
+    }
+
+    /// Profile topics are public; user topics are private to that user and
+    /// presence needs a signed-in user
+    pub fn allowed_for(&self, user: Option<&CurrentUser>) -> bool {
+        match self {
+            Topic::AllProfiles | Topic::Profile(_) => true,
+            Topic::Presence => user.is_some(),
+            Topic::User(id) => user.is_some_and(|u| u.id == *id),
+        }
+    }
+}
+
+impl FromStr for Topic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_id = |id: &str| {
+            id.parse::<i64>()
+                .map_err(|_| format!("Invalid id in topic: {s}"))
+        };
+
+        if s == "presence" {
+            return Ok(Topic::Presence);
+        }
+
+        match s.split_once(':') {
+            Some(("profiles", "*")) => Ok(Topic::AllProfiles),
+            Some(("profile", id)) => parse_id(id).map(Topic::Profile),
+            Some(("user", id)) => parse_id(id).map(Topic::User),
+            _ => Err(format!("Unknown topic: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Topic::AllProfiles => write!(f, "profiles:*"),
+            Topic::Profile(id) => write!(f, "profile:{id}"),
+            Topic::User(id) => write!(f, "user:{id}"),
+            Topic::Presence => write!(f, "presence"),
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{
+    config::ListenAddr,
+    shutdown::Shutdown,
+    state::AppState,
+    tls::{Certificates, TlsListener},
+};
+use anyhow::Context;
+use axum::{
+    extract::State,
+    http::{header::HOST, uri::Authority, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    serve::ListenerExt,
+    Router,
+};
+use futures_util::future::try_join_all;
+use std::{future::Future, net::SocketAddr};
+use tokio::{net::TcpListener, time};
+
+#[cfg(unix)]
+use axum::{extract::connect_info::Connected, serve::IncomingStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// A bound socket, ready to serve
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    /// Redirects every request to HTTPS on `https_port`
+    Redirect {
+        listener: TcpListener,
+        https_port: u16,
+    },
+    /// Removed again when the server stops
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+}
+
+/// `ConnectInfo` for connections over a Unix socket, whose peers have no
+/// address
+#[derive(Debug, Clone, Copy)]
+pub struct UnixPeer;
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for UnixPeer {
+    fn connect_info(_: IncomingStream<'_, UnixListener>) -> Self {
+        Self
+    }
+}
+
+/// Bind every address in `addrs`. A hostname is bound on each address it
+/// resolves to, and a stale Unix socket left by a previous run is replaced.
+pub async fn bind(addrs: &[ListenAddr]) -> anyhow::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        match addr {
+            ListenAddr::Tcp { host, port } => {
+                let mut resolved: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), *port))
+                    .await
+                    .with_context(|| format!("Failed to resolve {}", host))?
+                    .collect();
+                // A name can resolve to the same address more than once,
+                // and not necessarily in a row
+                resolved.sort();
+                resolved.dedup();
+                anyhow::ensure!(!resolved.is_empty(), "{} has no addresses", host);
+
+                for socket_addr in resolved {
+                    let listener = TcpListener::bind(socket_addr)
+                        .await
+                        .with_context(|| format!("Failed to listen on {}", socket_addr))?;
+                    tracing::info!("Listening on {}", listener.local_addr()?);
+                    listeners.push(Listener::Tcp(listener));
+                }
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to listen on {}", path.display()))?;
+                tracing::info!("Listening on unix:{}", path.display());
+                listeners.push(Listener::Unix {
+                    listener,
+                    path: path.clone(),
+                });
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(path) => {
+                anyhow::bail!(
+                    "Unix sockets aren't supported on this platform: {}",
+                    path.display()
+                )
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+/// Serve HTTPS with `certificates` on every TCP listener. Unix sockets stay
+/// plain, since whatever is on the other end is on the same machine.
+pub fn secure(
+    listeners: Vec<Listener>,
+    certificates: &Certificates,
+) -> anyhow::Result<Vec<Listener>> {
+    let acceptor = certificates.acceptor()?;
+    listeners
+        .into_iter()
+        .map(|listener| match listener {
+            Listener::Tcp(listener) => {
+                Ok(Listener::Tls(TlsListener::new(listener, acceptor.clone())?))
+            }
+            other => Ok(other),
+        })
+        .collect()
+}
+
+/// Bind `addrs` to redirect plain HTTP to HTTPS on `https_port`
+pub async fn bind_redirects(
+    addrs: &[ListenAddr],
+    https_port: u16,
+) -> anyhow::Result<Vec<Listener>> {
+    bind(addrs)
+        .await?
+        .into_iter()
+        .map(|listener| match listener {
+            Listener::Tcp(listener) => Ok(Listener::Redirect {
+                listener,
+                https_port,
+            }),
+            _ => anyhow::bail!("HTTPS redirects can only be served over TCP"),
+        })
+        .collect()
+}
+
+/// The port HTTPS is served on, for redirects: the first TLS listener's
+pub fn https_port(listeners: &[Listener]) -> Option<u16> {
+    listeners.iter().find_map(|listener| match listener {
+        Listener::Tls(listener) => axum::serve::Listener::local_addr(listener)
+            .ok()
+            .map(|addr| addr.port()),
+        _ => None,
+    })
+}
+
+/// Delete a socket file left behind by a server that didn't shut down
+/// cleanly. Anything else at `path` is left alone, so binding fails.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Serve `app` on every listener until `signal` resolves, then shut down:
+/// 1. Stop accepting connections and tell every realtime connection to
+///    close with "Service Restart"
+/// 2. Wait for in-flight requests and open connections to finish, for at
+///    most the configured grace period
+/// 3. Relay any events still in the outbox and close the database
+pub async fn serve(
+    listeners: Vec<Listener>,
+    app: Router,
+    state: AppState,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let shutdown = state.shutdown.clone();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            signal.await;
+            tracing::info!("Shutting down");
+            shutdown.trigger();
+        }
+    });
+
+    let servers = listeners
+        .into_iter()
+        .map(|listener| serve_listener(listener, app.clone(), shutdown.clone()));
+    let drained = async {
+        try_join_all(servers).await?;
+        state.connections.drained().await;
+        Ok::<_, std::io::Error>(())
+    };
+    let deadline = async {
+        shutdown.wait().await;
+        time::sleep(state.config.shutdown_grace_period()).await;
+    };
+    tokio::select! {
+        result = drained => result?,
+        () = deadline => {
+            tracing::warn!("Shutdown grace period elapsed with requests or connections still open");
+        }
+    }
+
+    if let Err(e) = state.events.relay_pending().await {
+        tracing::error!("Failed to relay events during shutdown: {}", e);
+    }
+    state.db.close().await;
+    tracing::info!("Shutdown complete");
+    Ok(())
+}
+
+/// Serve one listener until shutdown is triggered and its requests finish
+async fn serve_listener(
+    listener: Listener,
+    app: Router,
+    shutdown: Shutdown,
+) -> std::io::Result<()> {
+    let stopping = async move { shutdown.wait().await };
+    match listener {
+        Listener::Tcp(listener) => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(stopping)
+            .await
+        }
+        // The no-op tap gives TLS connections the same `ConnectInfo` as
+        // plain TCP ones
+        Listener::Tls(listener) => {
+            axum::serve(
+                listener.tap_io(|_| {}),
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(stopping)
+            .await
+        }
+        Listener::Redirect {
+            listener,
+            https_port,
+        } => {
+            let redirect = Router::new()
+                .fallback(redirect_to_https)
+                .with_state(https_port);
+            axum::serve(listener, redirect)
+                .with_graceful_shutdown(stopping)
+                .await
+        }
+        // Peers have no IP address, so connections over a Unix socket
+        // aren't capped per IP unless they come from a trusted proxy
+        #[cfg(unix)]
+        Listener::Unix { listener, path } => {
+            let result = axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<UnixPeer>(),
+            )
+            .with_graceful_shutdown(stopping)
+            .await;
+            std::fs::remove_file(&path).ok();
+            result
+        }
+    }
+}
+
+/// Send a plain HTTP request to the same host and path over HTTPS
+async fn redirect_to_https(
+    State(https_port): State<u16>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let Some(host) = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+
+    let authority = match https_port {
+        443 => host.host().to_string(),
+        port => format!("{}:{}", host.host(), port),
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+}
@@ -0,0 +1,51 @@
+use crate::{error::AppError, state::AppState};
+use db::SessionRow;
+use domain::SessionStatus;
+use tower_cookies::{cookie::time, Cookie, Cookies};
+
+pub const SESSION_COOKIE: &str = "session_id";
+
+/// Hand a session token to the client. Max-Age tracks the idle timeout, so
+/// every renewal pushes the browser-side expiry forward too.
+pub fn set_session_cookie(state: &AppState, cookies: &Cookies, token: String) {
+    let idle = state.sessions.lifetimes().idle;
+    let mut cookie = Cookie::new(SESSION_COOKIE, token);
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_max_age(time::Duration::seconds(idle.as_secs() as i64));
+    cookies.add(cookie);
+}
+
+pub fn clear_session_cookie(cookies: &Cookies) {
+    let mut cookie = Cookie::from(SESSION_COOKIE);
+    cookie.set_path("/");
+    cookies.remove(cookie);
+}
+
+/// Resolve the request's session cookie, sliding its expiry forward.
+/// Distinguishes an expired session from a missing or unknown one.
+pub async fn current_session(state: &AppState, cookies: &Cookies) -> Result<SessionRow, AppError> {
+    let token = cookies
+        .get(SESSION_COOKIE)
+        .ok_or(AppError::Unauthorized)?
+        .value()
+        .to_string();
+
+    let status = state
+        .sessions
+        .resolve_session(&token)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    match status {
+        SessionStatus::Active(session) => {
+            set_session_cookie(state, cookies, token);
+            Ok(session)
+        }
+        SessionStatus::Expired => {
+            clear_session_cookie(cookies);
+            Err(AppError::SessionExpired)
+        }
+        SessionStatus::Missing => Err(AppError::Unauthorized),
+    }
+}
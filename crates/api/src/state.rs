@@ -1,35 +1,86 @@
-use crate::config::Config;
+use crate::{
+    config::{Config, EventBusKind, TrustedProxies},
+    limits::ConnectionLimiter,
+    metrics::Metrics,
+    shutdown::Shutdown,
+};
+use anyhow::Context;
 use db::DbPool;
-use domain::ProfileService;
-use shared::types::WsEvent;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{broadcast, RwLock};
-
-pub type Sessions = Arc<RwLock<HashMap<String, i64>>>;
+use domain::{
+    EventBus, EventLog, InProcessBus, PollingBus, PostgresBus, PresenceRegistry, ProfileService,
+    Published, SessionLifetimes, SessionService,
+};
+use std::sync::Arc;
+use tokio::sync::broadcast;
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
     pub db: DbPool,
     pub profile_service: ProfileService,
-    pub sessions: Sessions,
-    events_tx: broadcast::Sender<WsEvent>,
+    pub sessions: SessionService,
+    pub events: EventLog,
+    pub presence: PresenceRegistry,
+    pub connections: ConnectionLimiter,
+    /// Parsed from `config.trusted_proxies`
+    pub trusted_proxies: Arc<TrustedProxies>,
+    pub metrics: Arc<Metrics>,
+    pub shutdown: Shutdown,
 }
 
 impl AppState {
-    pub fn new(config: Config, db: DbPool) -> Self {
-        let (events_tx, _) = broadcast::channel(100);
-        let profile_service = ProfileService::new(db.clone(), events_tx.clone());
-        Self {
+    pub fn new(config: Config, db: DbPool) -> anyhow::Result<Self> {
+        let events = EventLog::new(db.clone(), event_bus(&config, &db)?)
+            .with_coalesce_window(config.event_coalesce_window());
+        let profile_service = ProfileService::new(db.clone(), events.clone());
+        let sessions = SessionService::new(
+            db.clone(),
+            events.clone(),
+            SessionLifetimes {
+                absolute: config.session_max_age(),
+                idle: config.session_idle_timeout(),
+            },
+        );
+        let presence = PresenceRegistry::new(config.event_channel_capacity);
+        let metrics = Arc::<Metrics>::default();
+        let connections = ConnectionLimiter::new(&config, metrics.clone());
+        let trusted_proxies = Arc::new(config.trusted_proxies()?);
+        Ok(Self {
             config: Arc::new(config),
             db,
             profile_service,
-            events_tx,
-            sessions: Arc::new(RwLock::new(HashMap::new())),
-        }
+            sessions,
+            events,
+            presence,
+            connections,
+            trusted_proxies,
+            metrics,
+            shutdown: Shutdown::new(),
+        })
     }
 
-    pub fn subscribe_events(&self) -> broadcast::Receiver<WsEvent> {
-        self.events_tx.subscribe()
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Published> {
+        self.events.subscribe()
     }
 }
+
+fn event_bus(config: &Config, db: &DbPool) -> anyhow::Result<Arc<dyn EventBus>> {
+    let capacity = config.event_channel_capacity;
+    let coalesce = !config.event_coalesce_window().is_zero();
+    Ok(match config.event_bus {
+        EventBusKind::InProcess => Arc::new(InProcessBus::new(capacity)),
+        EventBusKind::Polling => Arc::new(PollingBus::new(
+            db.clone(),
+            capacity,
+            config.event_bus_poll_interval(),
+            coalesce,
+        )),
+        EventBusKind::Postgres => {
+            let url = config
+                .event_bus_postgres_url
+                .as_deref()
+                .context("APP__EVENT_BUS_POSTGRES_URL is required by the postgres event bus")?;
+            Arc::new(PostgresBus::new(url, capacity, coalesce)?)
+        }
+    })
+}
@@ -0,0 +1,57 @@
+use crate::{state::AppState, tls::Certificates};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Periodically delete sessions past their absolute or idle lifetime.
+pub fn spawn_session_sweeper(state: AppState) -> JoinHandle<()> {
+    let period = state.config.session_sweep_interval();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match state.sessions.purge_expired().await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Purged {} expired sessions", n),
+                Err(e) => tracing::error!("Failed to purge expired sessions: {}", e),
+            }
+        }
+    })
+}
+
+/// Periodically trim the event log to the configured retention.
+pub fn spawn_event_log_compactor(state: AppState) -> JoinHandle<()> {
+    let period = state.config.event_log_compact_interval();
+    let retain = state.config.event_log_retention;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match state.events.compact(retain).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Compacted {} events from the event log", n),
+                Err(e) => tracing::error!("Failed to compact event log: {}", e),
+            }
+        }
+    })
+}
+
+/// Broadcast events from the outbox as they're committed.
+pub fn spawn_event_relay(state: AppState) -> JoinHandle<()> {
+    let poll_interval = state.config.event_relay_poll_interval();
+    tokio::spawn(state.events.clone().run_relay(poll_interval))
+}
+
+/// Periodically pick up a renewed TLS certificate or key.
+pub fn spawn_certificate_reloader(certificates: Certificates, period: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match certificates.reload_if_changed() {
+                Ok(false) => {}
+                Ok(true) => tracing::info!("Reloaded TLS certificate"),
+                Err(e) => tracing::error!("Failed to reload TLS certificate: {:#}", e),
+            }
+        }
+    })
+}
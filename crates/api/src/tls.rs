@@ -0,0 +1,178 @@
+use anyhow::Context;
+use axum::serve::Listener;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+/// How long a client gets to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshakes finished but not yet picked up by the server
+const ACCEPT_BACKLOG: usize = 64;
+
+/// When a file was last changed, to notice it being replaced
+type Stamp = Option<(SystemTime, u64)>;
+
+/// The certificate and key being served, reloaded from disk when they
+/// change. Cloning shares them.
+#[derive(Clone, Debug)]
+pub struct Certificates {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: Arc<RwLock<Arc<CertifiedKey>>>,
+    stamps: Arc<Mutex<(Stamp, Stamp)>>,
+}
+
+impl Certificates {
+    pub fn load(cert_path: &Path, key_path: &Path) -> anyhow::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let stamps = (stamp(cert_path), stamp(key_path));
+        let key = read_certified_key(cert_path, key_path, &provider)?;
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            provider,
+            current: Arc::new(RwLock::new(Arc::new(key))),
+            stamps: Arc::new(Mutex::new(stamps)),
+        })
+    }
+
+    /// Serve the files' new contents if either has changed since they were
+    /// last read. Returns whether they were reloaded; on error the previous
+    /// certificate stays in use.
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let stamps = (stamp(&self.cert_path), stamp(&self.key_path));
+        let mut last = self.stamps.lock().unwrap();
+        if *last == stamps {
+            return Ok(false);
+        }
+        // Don't retry a broken pair until it changes again
+        *last = stamps;
+
+        let key = read_certified_key(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(true)
+    }
+
+    /// A TLS acceptor that always serves the current certificate
+    pub fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(self.clone()));
+        // Only HTTP/1.1 is served; offering h2 would strand clients that
+        // take it up
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn read_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> anyhow::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", cert_path.display()))?;
+    anyhow::ensure!(
+        !chain.is_empty(),
+        "No certificates in {}",
+        cert_path.display()
+    );
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read private key from {}", key_path.display()))?;
+    CertifiedKey::from_der(chain, key, provider).with_context(|| {
+        format!(
+            "{} isn't a usable key for {}",
+            key_path.display(),
+            cert_path.display()
+        )
+    })
+}
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Accepts TLS connections on a TCP listener. Handshakes run concurrently,
+/// so a slow client doesn't hold up the others.
+pub struct TlsListener {
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Start accepting on `listener`; stops when this is dropped
+    pub fn new(mut listener: TcpListener, acceptor: TlsAcceptor) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, handshaken) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = Listener::accept(&mut listener) => accepted,
+                    () = tx.closed() => break,
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => tracing::debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            handshaken,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only stops once we're dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
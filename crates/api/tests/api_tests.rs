@@ -2,6 +2,7 @@ mod common;
 
 use axum::http::StatusCode;
 use serde_json::json;
+use std::{net::SocketAddr, time::Duration};
 
 #[tokio::test]
 async fn health_check() {
@@ -183,3 +184,2444 @@ async fn update_profile_unauthorized_no_session() {
 
     response.assert_status(StatusCode::UNAUTHORIZED);
 }
+
+#[tokio::test]
+async fn session_survives_restart() {
+    let db = common::TempDb::new();
+    let mut app = common::TestApp::with_database(&db.url()).await;
+
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "restart@example.com",
+                "password": "password123",
+                "display_name": "Restart User"
+            }),
+        )
+        .await;
+
+    response.assert_ok();
+    let profile_id = response.json()["profile"]["id"].as_i64().unwrap();
+
+    // A fresh app on the same database still accepts the session cookie
+    let mut restarted = common::TestApp::with_database(&db.url()).await;
+    restarted.set_cookies(app.cookies());
+
+    let response = restarted
+        .patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({
+                "bio": "Still here"
+            }),
+        )
+        .await;
+
+    response.assert_ok();
+}
+
+#[tokio::test]
+async fn logout_ends_session() {
+    let mut app = common::TestApp::new().await;
+
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "logout@example.com",
+                "password": "password123",
+                "display_name": "Logout User"
+            }),
+        )
+        .await;
+
+    response.assert_ok();
+    let profile_id = response.json()["profile"]["id"].as_i64().unwrap();
+    let cookies = app.cookies();
+
+    app.post("/api/auth/logout", json!({})).await.assert_ok();
+
+    // The old cookie no longer authenticates
+    app.set_cookies(cookies);
+    let response = app
+        .patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({
+                "bio": "After logout"
+            }),
+        )
+        .await;
+
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn session_cookie_has_max_age() {
+    let mut app = common::TestApp::new().await;
+
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "maxage@example.com",
+                "password": "password123",
+                "display_name": "Max Age"
+            }),
+        )
+        .await;
+
+    response.assert_ok();
+    let cookie = response.set_cookie.unwrap();
+    assert!(cookie.contains("Max-Age="), "cookie: {cookie}");
+}
+
+#[tokio::test]
+async fn idle_session_expires() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        session_idle_timeout_secs: 1,
+        ..common::test_config()
+    })
+    .await;
+
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "idle@example.com",
+                "password": "password123",
+                "display_name": "Idle User"
+            }),
+        )
+        .await;
+
+    response.assert_ok();
+    let profile_id = response.json()["profile"]["id"].as_i64().unwrap();
+
+    // SQLite timestamps have one-second resolution
+    tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+
+    let response = app
+        .patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({
+                "bio": "Too late"
+            }),
+        )
+        .await;
+
+    response.assert_status(StatusCode::UNAUTHORIZED);
+    assert_eq!(response.json()["error"], "Session expired");
+}
+
+/// Register a user, then log in again so the app holds a second session.
+/// Returns the cookie of the first session.
+async fn register_with_two_sessions(app: &mut common::TestApp, email: &str) -> Option<String> {
+    app.post(
+        "/api/auth/register",
+        json!({
+            "email": email,
+            "password": "password123",
+            "display_name": "Multi Device"
+        }),
+    )
+    .await
+    .assert_ok();
+    let first = app.cookies();
+
+    app.post(
+        "/api/auth/login",
+        json!({
+            "email": email,
+            "password": "password123"
+        }),
+    )
+    .await
+    .assert_ok();
+
+    first
+}
+
+#[tokio::test]
+async fn list_and_revoke_sessions() {
+    let mut app = common::TestApp::new().await;
+    let first = register_with_two_sessions(&mut app, "sessions@example.com").await;
+
+    let response = app.get("/api/auth/sessions").await;
+    response.assert_ok();
+    let sessions = response.json();
+    let sessions = sessions.as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions.iter().filter(|s| s["current"] == true).count(), 1);
+
+    let other_id = sessions.iter().find(|s| s["current"] == false).unwrap()["id"]
+        .as_i64()
+        .unwrap();
+
+    let response = app
+        .delete(&format!("/api/auth/sessions/{}", other_id))
+        .await;
+    response.assert_ok();
+    assert_eq!(response.json()["revoked"], 1);
+
+    let response = app.get("/api/auth/sessions").await;
+    assert_eq!(response.json().as_array().unwrap().len(), 1);
+
+    // The revoked session is gone for good
+    let response = app
+        .delete(&format!("/api/auth/sessions/{}", other_id))
+        .await;
+    response.assert_status(StatusCode::NOT_FOUND);
+
+    app.set_cookies(first);
+    app.get("/api/auth/sessions")
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn logout_all_keeps_current_session() {
+    let mut app = common::TestApp::new().await;
+    let first = register_with_two_sessions(&mut app, "logoutall@example.com").await;
+
+    let response = app.post("/api/auth/logout-all", json!({})).await;
+    response.assert_ok();
+    assert_eq!(response.json()["revoked"], 1);
+
+    app.get("/api/auth/sessions").await.assert_ok();
+
+    app.set_cookies(first);
+    app.get("/api/auth/sessions")
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn revoking_session_closes_websocket() {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::new().await;
+    let first = register_with_two_sessions(&mut app, "wsrevoke@example.com").await;
+    let second = app.cookies();
+    let addr = app.serve().await;
+
+    app.set_cookies(first);
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+
+    app.set_cookies(second);
+    app.post("/api/auth/logout-all", json!({}))
+        .await
+        .assert_ok();
+
+    let close = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while let Some(msg) = ws.next().await {
+            if let Message::Close(frame) = msg.unwrap() {
+                return frame;
+            }
+        }
+        None
+    })
+    .await
+    .expect("socket was not closed");
+
+    // 4001 = session revoked
+    assert_eq!(u16::from(close.unwrap().code), 4001);
+}
+
+#[tokio::test]
+async fn replayed_revocation_spares_the_next_session() {
+    let mut app = common::TestApp::new().await;
+    register_profile(&mut app, "relogin@example.com", "Relogin").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    let head = common::recv_frames(&mut ws).await[0]["seq"]
+        .as_i64()
+        .unwrap();
+    drop(ws);
+
+    // Logging out revokes the newest session, and logging back in must not
+    // get its id back
+    app.post("/api/auth/logout", json!({})).await.assert_ok();
+    app.post(
+        "/api/auth/login",
+        json!({ "email": "relogin@example.com", "password": "password123" }),
+    )
+    .await
+    .assert_ok();
+
+    // Resuming replays the old revocation, which isn't about this socket
+    let mut ws = app
+        .connect_ws(addr, &format!("/api/ws?topics=profiles:*&since={}", head))
+        .await;
+    assert_eq!(
+        common::recv_close_code(&mut ws, Duration::from_secs(1)).await,
+        None
+    );
+}
+
+/// Status code of a rejected WebSocket upgrade
+fn upgrade_status(err: tokio_tungstenite::tungstenite::Error) -> u16 {
+    match err {
+        tokio_tungstenite::tungstenite::Error::Http(response) => response.status().as_u16(),
+        other => panic!("expected HTTP rejection, got {other}"),
+    }
+}
+
+#[tokio::test]
+async fn websocket_requires_session() {
+    let app = common::TestApp::new().await;
+    let addr = app.serve().await;
+
+    let err = app.try_connect_ws(addr, "/api/ws").await.unwrap_err();
+    assert_eq!(upgrade_status(err), 401);
+}
+
+#[tokio::test]
+async fn websocket_public_feed_allows_anonymous() {
+    let app = common::TestApp::with_config(api::config::Config {
+        ws_public_feed: true,
+        ..common::test_config()
+    })
+    .await;
+    let addr = app.serve().await;
+
+    app.try_connect_ws(addr, "/api/ws").await.unwrap();
+}
+
+#[tokio::test]
+async fn websocket_public_feed_keeps_anonymous_clients_off_private_topics() {
+    let app = common::TestApp::with_config(api::config::Config {
+        ws_public_feed: true,
+        ..common::test_config()
+    })
+    .await;
+    let addr = app.serve().await;
+
+    let err = app
+        .try_connect_ws(addr, "/api/ws?topics=presence")
+        .await
+        .unwrap_err();
+    assert_eq!(upgrade_status(err), 401);
+}
+
+#[tokio::test]
+async fn websocket_public_feed_recognises_session_cookie() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_public_feed: true,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "feed@example.com", "Feed User").await;
+    let addr = app.serve().await;
+
+    app.try_connect_ws(addr, "/api/ws?topics=presence")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn websocket_public_feed_treats_expired_cookie_as_anonymous() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_public_feed: true,
+        session_idle_timeout_secs: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "stale@example.com", "Stale User").await;
+    let addr = app.serve().await;
+
+    // SQLite timestamps have one-second resolution
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+
+    app.try_connect_ws(addr, "/api/ws?topics=profiles:*")
+        .await
+        .unwrap();
+    let err = app
+        .try_connect_ws(addr, "/api/ws?topics=presence")
+        .await
+        .unwrap_err();
+    assert_eq!(upgrade_status(err), 401);
+}
+
+#[tokio::test]
+async fn websocket_ticket_is_single_use() {
+    let mut app = common::TestApp::new().await;
+    app.post(
+        "/api/auth/register",
+        json!({
+            "email": "ticket@example.com",
+            "password": "password123",
+            "display_name": "Ticket User"
+        }),
+    )
+    .await
+    .assert_ok();
+    let addr = app.serve().await;
+
+    let response = app.post("/api/auth/ws-ticket", json!({})).await;
+    response.assert_ok();
+    let ticket = response.json()["ticket"].as_str().unwrap().to_string();
+
+    // Connect without the cookie, using only the ticket
+    app.set_cookies(None);
+    let path = format!("/api/ws?ticket={ticket}");
+    // Keep it open: dropping it while the server is still reading its
+    // initial state would cancel a query on the in-memory database's only
+    // connection, and the pool would replace that with an empty database
+    let _ws = app.try_connect_ws(addr, &path).await.unwrap();
+
+    let err = app.try_connect_ws(addr, &path).await.unwrap_err();
+    assert_eq!(upgrade_status(err), 401);
+}
+
+#[tokio::test]
+async fn websocket_ticket_survives_a_rejected_connection() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_connections_per_user: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "ticket-cap@example.com", "Ticket Cap").await;
+    let addr = app.serve().await;
+    let mut first = app.connect_ws(addr, "/api/ws").await;
+    common::recv_frames(&mut first).await;
+
+    let response = app.post("/api/auth/ws-ticket", json!({})).await;
+    response.assert_ok();
+    let ticket = response.json()["ticket"].as_str().unwrap().to_string();
+
+    // Over the cap, so the ticket isn't used up
+    app.set_cookies(None);
+    let path = format!("/api/ws?ticket={ticket}");
+    let err = app.try_connect_ws(addr, &path).await.unwrap_err();
+    assert_eq!(upgrade_status(err), 429);
+
+    // Once the slot frees up, the same ticket gets in
+    first.close(None).await.unwrap();
+    let mut reopened = None;
+    for _ in 0..20 {
+        match app.try_connect_ws(addr, &path).await {
+            Ok(ws) => {
+                reopened = Some(ws);
+                break;
+            }
+            Err(err) => assert_eq!(upgrade_status(err), 429),
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reopened.is_some());
+}
+
+#[tokio::test]
+async fn websocket_resumes_from_sequence() {
+    let mut app = common::TestApp::new().await;
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "resume@example.com",
+                "password": "password123",
+                "display_name": "Resume User"
+            }),
+        )
+        .await;
+    response.assert_ok();
+    let profile_id = response.json()["profile"]["id"].as_i64().unwrap();
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    let snapshot = common::recv_frames(&mut ws).await;
+    assert_eq!(snapshot.len(), 1);
+    let head = snapshot[0]["seq"].as_i64().unwrap();
+    drop(ws);
+
+    // Changes made while disconnected
+    for bio in ["one", "two"] {
+        app.patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({ "bio": bio }),
+        )
+        .await
+        .assert_ok();
+    }
+
+    let mut ws = app
+        .connect_ws(addr, &format!("/api/ws?topics=profiles:*&since={}", head))
+        .await;
+    let frames = common::recv_frames(&mut ws).await;
+    let seqs: Vec<i64> = frames.iter().map(|f| f["seq"].as_i64().unwrap()).collect();
+    assert_eq!(seqs, vec![head + 1, head + 2]);
+    assert_eq!(frames[1]["data"]["changed_fields"]["bio"], "two");
+}
+
+#[tokio::test]
+async fn websocket_snapshot_when_since_is_unknown() {
+    let mut app = common::TestApp::new().await;
+    app.post(
+        "/api/auth/register",
+        json!({
+            "email": "unknown-since@example.com",
+            "password": "password123",
+            "display_name": "Snapshot User"
+        }),
+    )
+    .await
+    .assert_ok();
+    let addr = app.serve().await;
+
+    // A position the log has never reached can't be resumed from
+    let mut ws = app
+        .connect_ws(addr, "/api/ws?topics=profiles:*&since=1000")
+        .await;
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "Snapshot");
+    let profiles = frames[0]["data"]["profiles"].as_array().unwrap();
+    assert_eq!(profiles.len(), 1);
+    assert_eq!(profiles[0]["display_name"], "Snapshot User");
+    assert_eq!(frames[0]["data"]["cursor"], frames[0]["seq"]);
+}
+
+#[tokio::test]
+async fn websocket_update_profile_command() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::new().await;
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "command@example.com",
+                "password": "password123",
+                "display_name": "Command User"
+            }),
+        )
+        .await;
+    response.assert_ok();
+    let profile_id = response.json()["profile"]["id"].as_i64().unwrap();
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    let request = json!({
+        "request_id": "r1",
+        "type": "UpdateProfile",
+        "data": { "id": profile_id, "bio": "Set over the socket" }
+    });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+
+    let frames = common::recv_frames(&mut ws).await;
+    let reply = frames
+        .iter()
+        .find(|f| f["request_id"] == "r1")
+        .expect("no reply");
+    assert_eq!(reply["type"], "ProfileUpdated");
+    assert_eq!(reply["data"]["bio"], "Set over the socket");
+
+    // The change is also broadcast like any other update
+    assert!(frames.iter().any(|f| f["type"] == "ProfilePatch"
+        && f["data"]["changed_fields"]["bio"] == "Set over the socket"));
+}
+
+#[tokio::test]
+async fn websocket_command_errors() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::new().await;
+    app.post(
+        "/api/auth/register",
+        json!({
+            "email": "command-errors@example.com",
+            "password": "password123",
+            "display_name": "Command Errors"
+        }),
+    )
+    .await
+    .assert_ok();
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    common::recv_frames(&mut ws).await;
+
+    // Someone else's (nonexistent) profile
+    let request = json!({
+        "request_id": "r1",
+        "type": "UpdateProfile",
+        "data": { "id": 9999, "bio": "Nope" }
+    });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+    // Unknown command, id still echoed back
+    let request = json!({ "request_id": "r2", "type": "Explode" });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+    // Not JSON at all
+    ws.send(Message::Text("not json".into())).await.unwrap();
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[0]["request_id"], "r1");
+    assert_eq!(frames[0]["type"], "Error");
+    assert_eq!(frames[0]["data"]["code"], "not_found");
+    assert_eq!(frames[1]["request_id"], "r2");
+    assert_eq!(frames[1]["data"]["code"], "bad_request");
+    assert!(frames[2]["request_id"].is_null());
+    assert_eq!(frames[2]["data"]["code"], "bad_request");
+}
+
+/// Register a user and return their profile id
+async fn register_profile(app: &mut common::TestApp, email: &str, name: &str) -> i64 {
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": email,
+                "password": "password123",
+                "display_name": name
+            }),
+        )
+        .await;
+    response.assert_ok();
+    response.json()["profile"]["id"].as_i64().unwrap()
+}
+
+#[tokio::test]
+async fn websocket_without_topics_receives_nothing() {
+    let mut app = common::TestApp::new().await;
+    let profile_id = register_profile(&mut app, "no-topics@example.com", "Quiet").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    app.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "bio": "Unheard" }),
+    )
+    .await
+    .assert_ok();
+
+    assert!(common::recv_frames(&mut ws).await.is_empty());
+}
+
+#[tokio::test]
+async fn websocket_subscribe_command() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::new().await;
+    let profile_id = register_profile(&mut app, "subscribe@example.com", "Subscriber").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    let request = json!({
+        "request_id": "s1",
+        "type": "Subscribe",
+        "data": { "topics": [format!("profile:{profile_id}")] }
+    });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+
+    // The topic's current state, then the reply
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["type"], "Snapshot");
+    assert_eq!(frames[0]["data"]["profiles"][0]["id"], profile_id);
+    assert_eq!(frames[1]["request_id"], "s1");
+    assert_eq!(frames[1]["type"], "Subscribed");
+
+    let request = json!({
+        "request_id": "s2",
+        "type": "Unsubscribe",
+        "data": { "topics": [format!("profile:{profile_id}")] }
+    });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+    common::recv_frames(&mut ws).await;
+
+    app.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "bio": "After unsubscribing" }),
+    )
+    .await
+    .assert_ok();
+    assert!(common::recv_frames(&mut ws).await.is_empty());
+}
+
+#[tokio::test]
+async fn websocket_profile_topic_filters_events() {
+    let mut app = common::TestApp::new().await;
+    let other_id = register_profile(&mut app, "other@example.com", "Other").await;
+    let other = app.cookies();
+    let own_id = register_profile(&mut app, "watcher@example.com", "Watcher").await;
+    let addr = app.serve().await;
+
+    let mut ws = app
+        .connect_ws(addr, &format!("/api/ws?topics=profile:{own_id}"))
+        .await;
+    common::recv_frames(&mut ws).await;
+
+    app.patch(
+        &format!("/api/profiles/{}", own_id),
+        json!({ "bio": "Mine" }),
+    )
+    .await
+    .assert_ok();
+    app.set_cookies(other);
+    app.patch(
+        &format!("/api/profiles/{}", other_id),
+        json!({ "bio": "Theirs" }),
+    )
+    .await
+    .assert_ok();
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["data"]["changed_fields"]["bio"], "Mine");
+}
+
+#[tokio::test]
+async fn profile_updates_are_sent_as_patches() {
+    let mut app = common::TestApp::new().await;
+    let profile_id = register_profile(&mut app, "patch@example.com", "Patchy").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames[0]["data"]["profiles"][0]["version"], 1);
+
+    app.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "bio": "Patched" }),
+    )
+    .await
+    .assert_ok();
+    app.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "display_name": "Renamed" }),
+    )
+    .await
+    .assert_ok();
+    // Setting nothing isn't a change
+    app.patch(&format!("/api/profiles/{}", profile_id), json!({}))
+        .await
+        .assert_ok();
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["type"], "ProfilePatch");
+    assert_eq!(frames[0]["data"]["base_version"], 1);
+    assert_eq!(frames[0]["data"]["version"], 2);
+    assert_eq!(
+        frames[0]["data"]["changed_fields"],
+        json!({ "bio": "Patched" })
+    );
+    assert_eq!(frames[1]["data"]["base_version"], 2);
+    assert_eq!(frames[1]["data"]["version"], 3);
+    assert_eq!(
+        frames[1]["data"]["changed_fields"],
+        json!({ "display_name": "Renamed" })
+    );
+}
+
+#[tokio::test]
+async fn websocket_user_topic_requires_same_user() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::new().await;
+    register_profile(&mut app, "topic-owner@example.com", "Owner").await;
+    let addr = app.serve().await;
+
+    // User ids are assigned in order, so 9999 is someone else
+    let err = app
+        .try_connect_ws(addr, "/api/ws?topics=user:9999")
+        .await
+        .unwrap_err();
+    assert_eq!(upgrade_status(err), 401);
+    let err = app
+        .try_connect_ws(addr, "/api/ws?topics=bogus")
+        .await
+        .unwrap_err();
+    assert_eq!(upgrade_status(err), 400);
+
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    let request = json!({
+        "request_id": "u1",
+        "type": "Subscribe",
+        "data": { "topics": ["user:9999"] }
+    });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "Error");
+    assert_eq!(frames[0]["data"]["code"], "unauthorized");
+}
+
+#[tokio::test]
+async fn presence_tracks_open_websockets() {
+    let mut app = common::TestApp::new().await;
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "online-a@example.com",
+                "password": "password123",
+                "display_name": "Online A"
+            }),
+        )
+        .await;
+    response.assert_ok();
+    let a_id = response.json()["profile"]["user_id"].as_i64().unwrap();
+    let a = app.cookies();
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "online-b@example.com",
+                "password": "password123",
+                "display_name": "Online B"
+            }),
+        )
+        .await;
+    response.assert_ok();
+    let b_id = response.json()["profile"]["user_id"].as_i64().unwrap();
+    let addr = app.serve().await;
+
+    // B sees itself in the presence snapshot
+    let mut watcher = app.connect_ws(addr, "/api/ws?topics=presence").await;
+    let frames = common::recv_frames(&mut watcher).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "PresenceSnapshot");
+    assert_eq!(frames[0]["data"]["user_ids"], json!([b_id]));
+
+    // Two tabs for A, but only one join
+    let b = app.cookies();
+    app.set_cookies(a);
+    let mut tab1 = app.connect_ws(addr, "/api/ws").await;
+    let mut tab2 = app.connect_ws(addr, "/api/ws").await;
+    let frames = common::recv_frames(&mut watcher).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "PresenceJoined");
+    assert_eq!(frames[0]["data"]["user_id"], a_id);
+
+    app.set_cookies(b);
+    let response = app.get("/api/presence").await;
+    response.assert_ok();
+    assert_eq!(response.json()["user_ids"], json!([a_id, b_id]));
+
+    // A is online until its last tab closes
+    tab1.close(None).await.unwrap();
+    assert!(common::recv_frames(&mut watcher).await.is_empty());
+    tab2.close(None).await.unwrap();
+    let frames = common::recv_frames(&mut watcher).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "PresenceLeft");
+    assert_eq!(frames[0]["data"]["user_id"], a_id);
+
+    let response = app.get("/api/presence").await;
+    assert_eq!(response.json()["user_ids"], json!([b_id]));
+}
+
+#[tokio::test]
+async fn lagging_presence_is_resent_as_a_snapshot() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_channel_capacity: 1,
+        ..common::test_config()
+    })
+    .await;
+    let response = app
+        .post(
+            "/api/auth/register",
+            json!({
+                "email": "watcher@example.com",
+                "password": "password123",
+                "display_name": "Watcher"
+            }),
+        )
+        .await;
+    let watcher_id = response.json()["profile"]["user_id"].as_i64().unwrap();
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=presence").await;
+    common::recv_frames(&mut ws).await;
+
+    // More changes at once than the socket's channel holds, including one
+    // it would otherwise have to undo
+    let presence = &app.state().presence;
+    for user_id in [1001, 1002, 1003] {
+        presence.join(user_id);
+    }
+    presence.leave(1002);
+
+    let frames = common::recv_frames(&mut ws).await;
+    let snapshot = frames
+        .iter()
+        .find(|f| f["type"] == "PresenceSnapshot")
+        .expect("a presence snapshot");
+    assert_eq!(
+        snapshot["data"]["user_ids"],
+        json!([watcher_id, 1001, 1003])
+    );
+}
+
+#[tokio::test]
+async fn presence_requires_session() {
+    let app = common::TestApp::with_config(api::config::Config {
+        ws_public_feed: true,
+        ..common::test_config()
+    })
+    .await;
+    let addr = app.serve().await;
+
+    app.get("/api/presence")
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+    let err = app
+        .try_connect_ws(addr, "/api/ws?topics=presence")
+        .await
+        .unwrap_err();
+    assert_eq!(upgrade_status(err), 401);
+}
+
+#[tokio::test]
+async fn websocket_heartbeat_keeps_live_socket_open() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_ping_interval_secs: 1,
+        ws_pong_timeout_secs: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "heartbeat@example.com", "Heartbeat").await;
+    let addr = app.serve().await;
+
+    // Reading the socket answers the server's pings
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(4)).await;
+    assert_eq!(code, None);
+}
+
+#[tokio::test]
+async fn websocket_missed_heartbeat_closes_socket() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_ping_interval_secs: 1,
+        ws_pong_timeout_secs: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "dead-peer@example.com", "Dead Peer").await;
+    let addr = app.serve().await;
+
+    // A client that isn't reading doesn't answer pings. The first ping goes
+    // out after a second and is due a second later.
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    // 4003 = heartbeat timeout
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(2)).await;
+    assert_eq!(code, Some(4003));
+}
+
+#[tokio::test]
+async fn websocket_max_lifetime_closes_socket() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_lifetime_secs: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "lifetime@example.com", "Lifetime").await;
+    let addr = app.serve().await;
+
+    // 4004 = lifetime exceeded
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(3)).await;
+    assert_eq!(code, Some(4004));
+}
+
+#[tokio::test]
+async fn sse_snapshot_and_live_events() {
+    let mut app = common::TestApp::new().await;
+    let profile_id = register_profile(&mut app, "sse@example.com", "SSE User").await;
+
+    let mut sse = app.open_sse("/api/events?topics=profiles:*", None).await;
+    assert_eq!(sse.status, StatusCode::OK);
+    let snapshot = sse.recv_events().await;
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].data["type"], "Snapshot");
+    assert_eq!(
+        snapshot[0].data["data"]["profiles"][0]["display_name"],
+        "SSE User"
+    );
+    // The event id is the sequence number, same as the JSON's `seq`
+    assert_eq!(snapshot[0].id, snapshot[0].data["seq"].as_i64());
+
+    app.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "bio": "Streamed" }),
+    )
+    .await
+    .assert_ok();
+    let events = sse.recv_events().await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].data["data"]["changed_fields"]["bio"], "Streamed");
+    assert_eq!(events[0].id, Some(snapshot[0].id.unwrap() + 1));
+}
+
+#[tokio::test]
+async fn stalled_client_gives_up_its_connection_slot() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_channel_capacity: 1,
+        ws_pong_timeout_secs: 1,
+        ..common::test_config()
+    })
+    .await;
+    let profile_id = register_profile(&mut app, "stalled@example.com", "Stalled").await;
+
+    // Never read, so once the snapshot fills the stream's buffer the next
+    // write can't go through
+    let sse = app.open_sse("/api/events?topics=profiles:*", None).await;
+    assert_eq!(sse.status, StatusCode::OK);
+    app.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "bio": "Stuck" }),
+    )
+    .await
+    .assert_ok();
+    assert_eq!(metric(&app, "ws_connections").await, 1);
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(metric(&app, "ws_connections").await, 0);
+    drop(sse);
+}
+
+#[tokio::test]
+async fn sse_resumes_from_last_event_id() {
+    let mut app = common::TestApp::new().await;
+    let profile_id = register_profile(&mut app, "sse-resume@example.com", "SSE Resume").await;
+
+    let mut sse = app.open_sse("/api/events?topics=profiles:*", None).await;
+    let head = sse.recv_events().await[0].id.unwrap();
+    drop(sse);
+
+    for bio in ["one", "two"] {
+        app.patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({ "bio": bio }),
+        )
+        .await
+        .assert_ok();
+    }
+
+    let mut sse = app
+        .open_sse("/api/events?topics=profiles:*", Some(head))
+        .await;
+    let events = sse.recv_events().await;
+    let ids: Vec<i64> = events.iter().map(|e| e.id.unwrap()).collect();
+    assert_eq!(ids, vec![head + 1, head + 2]);
+    assert_eq!(events[1].data["data"]["changed_fields"]["bio"], "two");
+}
+
+#[tokio::test]
+async fn sse_requires_session_and_ends_on_revocation() {
+    let mut app = common::TestApp::new().await;
+    let first = register_with_two_sessions(&mut app, "sse-revoke@example.com").await;
+    let second = app.cookies();
+
+    app.set_cookies(None);
+    let sse = app.open_sse("/api/events", None).await;
+    assert_eq!(sse.status, StatusCode::UNAUTHORIZED);
+
+    app.set_cookies(first);
+    let mut sse = app.open_sse("/api/events", None).await;
+    assert_eq!(sse.status, StatusCode::OK);
+
+    app.set_cookies(second);
+    app.post("/api/auth/logout-all", json!({}))
+        .await
+        .assert_ok();
+
+    let events = sse.recv_events().await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event.as_deref(), Some("close"));
+    assert_eq!(events[0].data["code"], 4001);
+}
+
+#[tokio::test]
+async fn websocket_snapshot_is_one_frame() {
+    let mut app = common::TestApp::new().await;
+    for i in 0..5 {
+        register_profile(
+            &mut app,
+            &format!("batch{i}@example.com"),
+            &format!("Batch {i}"),
+        )
+        .await;
+    }
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "Snapshot");
+    assert_eq!(frames[0]["data"]["profiles"].as_array().unwrap().len(), 5);
+
+    // Subscribing to presence alone doesn't produce an empty profile snapshot
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=presence").await;
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "PresenceSnapshot");
+}
+
+#[tokio::test]
+async fn deleting_account_sends_tombstones_and_closes_sockets() {
+    let mut app = common::TestApp::new().await;
+    register_profile(&mut app, "watcher2@example.com", "Watcher").await;
+    let watcher = app.cookies();
+    let profile_id = register_profile(&mut app, "goner@example.com", "Goner").await;
+    let addr = app.serve().await;
+
+    let mut own = app.connect_ws(addr, "/api/ws").await;
+    app.set_cookies(watcher);
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    app.set_cookies(None);
+    app.post(
+        "/api/auth/login",
+        json!({ "email": "goner@example.com", "password": "password123" }),
+    )
+    .await
+    .assert_ok();
+    app.delete("/api/auth/account")
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+
+    // 4001 = session revoked
+    let code = common::recv_close_code(&mut own, Duration::from_secs(5)).await;
+    assert_eq!(code, Some(4001));
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["type"], "Deleted");
+    assert_eq!(frames[0]["data"]["id"], profile_id);
+    assert_eq!(frames[1]["type"], "Removed");
+    assert_eq!(frames[1]["data"]["user_id"], frames[0]["data"]["user_id"]);
+
+    app.post(
+        "/api/auth/login",
+        json!({ "email": "goner@example.com", "password": "password123" }),
+    )
+    .await
+    .assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn undelivered_outbox_events_are_relayed() {
+    let db = common::TempDb::new();
+    let mut app = common::TestApp::with_config(api::config::Config {
+        database_url: db.url(),
+        event_relay_poll_interval_ms: 100,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "outbox@example.com", "Outbox").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    // An event written straight to the table, so nothing wakes the relay
+    let pool = db::pool::create_pool(&db.url()).await.unwrap();
+    let seq = db::append_event(&pool, r#"{"type":"Removed","data":{"user_id":42}}"#, None)
+        .await
+        .unwrap();
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["seq"], seq);
+    assert_eq!(frames[0]["type"], "Removed");
+    assert!(db::list_undelivered_events(&pool, None)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn interleaved_outbox_transactions_reach_websockets_in_order() {
+    use shared::types::WsEvent;
+
+    let db = common::TempDb::new();
+    let mut app = common::TestApp::with_config(api::config::Config {
+        database_url: db.url(),
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "interleave@example.com", "Interleave").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    // B starts writing while A's transaction is open, so it waits for A
+    // to commit
+    let mut a = app.state().events.begin().await.unwrap();
+    a.record(&WsEvent::Removed { user_id: 1 }).await.unwrap();
+    let b = tokio::spawn({
+        let events = app.state().events.clone();
+        async move {
+            let mut b = events.begin().await.unwrap();
+            b.record(&WsEvent::Removed { user_id: 2 }).await.unwrap();
+            b.commit().await.unwrap();
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    a.commit().await.unwrap();
+    b.await.unwrap();
+
+    let frames = common::recv_frames(&mut ws).await;
+    let removed: Vec<i64> = frames
+        .iter()
+        .map(|f| f["data"]["user_id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(removed, vec![1, 2]);
+    assert!(frames[0]["seq"].as_i64() < frames[1]["seq"].as_i64());
+}
+
+#[tokio::test]
+async fn websocket_fills_gaps_in_the_sequence_from_the_log() {
+    use shared::types::WsEvent;
+
+    let db = common::TempDb::new();
+    let mut app = common::TestApp::with_config(api::config::Config {
+        database_url: db.url(),
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "gap@example.com", "Gap").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    // An event the relay never publishes, so the next one arrives after
+    // a gap
+    let pool = db::pool::create_pool(&db.url()).await.unwrap();
+    let mut tx = pool.begin().await.unwrap();
+    let skipped = db::append_event(
+        &mut *tx,
+        r#"{"type":"Removed","data":{"user_id":41}}"#,
+        None,
+    )
+    .await
+    .unwrap();
+    db::mark_events_delivered(&mut *tx, skipped, None)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    app.state()
+        .events
+        .publish(WsEvent::Removed { user_id: 42 })
+        .await
+        .unwrap();
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["seq"], skipped);
+    assert_eq!(frames[0]["data"]["user_id"], 41);
+    assert_eq!(frames[1]["seq"], skipped + 1);
+    assert_eq!(frames[1]["data"]["user_id"], 42);
+}
+
+/// Put `payloads` in the outbox in one transaction, so the relay publishes
+/// them back to back, faster than any socket reads them
+async fn append_events(app: &common::TestApp, payloads: &[&str]) -> Vec<i64> {
+    let mut tx = app.state().db.begin().await.unwrap();
+    let mut seqs = Vec::new();
+    for payload in payloads {
+        seqs.push(db::append_event(&mut *tx, payload, None).await.unwrap());
+    }
+    tx.commit().await.unwrap();
+    seqs
+}
+
+#[tokio::test]
+async fn lagging_websocket_resyncs_from_the_log() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_channel_capacity: 1,
+        event_relay_poll_interval_ms: 100,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "lag@example.com", "Lag").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    let removed: Vec<String> = (1..=5)
+        .map(|id| format!(r#"{{"type":"Removed","data":{{"user_id":{id}}}}}"#))
+        .collect();
+    let payloads: Vec<&str> = removed.iter().map(String::as_str).collect();
+    let seqs = append_events(&app, &payloads).await;
+
+    // All but the last overflow the channel, and come from the log instead
+    let frames = common::recv_frames(&mut ws).await;
+    let received: Vec<i64> = frames.iter().map(|f| f["seq"].as_i64().unwrap()).collect();
+    assert_eq!(received, seqs);
+}
+
+#[tokio::test]
+async fn lagging_websocket_is_closed_when_resync_fails() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_channel_capacity: 1,
+        event_relay_poll_interval_ms: 100,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "resync@example.com", "Resync").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    // The relay skips the row it can't decode, but replaying the log
+    // after lagging can't
+    append_events(
+        &app,
+        &[
+            r#"{"type":"NotAnEvent"}"#,
+            r#"{"type":"Removed","data":{"user_id":1}}"#,
+            r#"{"type":"Removed","data":{"user_id":2}}"#,
+            r#"{"type":"Removed","data":{"user_id":3}}"#,
+        ],
+    )
+    .await;
+
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(5)).await;
+    assert_eq!(code, Some(4002));
+}
+
+#[tokio::test]
+async fn polling_bus_shares_events_between_instances() {
+    let db = common::TempDb::new();
+    let config = || api::config::Config {
+        database_url: db.url(),
+        event_bus: api::config::EventBusKind::Polling,
+        event_bus_poll_interval_ms: 50,
+        ..common::test_config()
+    };
+    let mut a = common::TestApp::with_config(config()).await;
+    let mut b = common::TestApp::with_config(config()).await;
+    let profile_id = register_profile(&mut a, "bus@example.com", "Bus").await;
+
+    // Sessions live in the database too, so the cookie works on either
+    b.set_cookies(a.cookies());
+    let addr = b.serve().await;
+    let mut ws = b.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames[0]["data"]["profiles"][0]["id"], profile_id);
+
+    a.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "bio": "Sent through A" }),
+    )
+    .await
+    .assert_ok();
+
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "ProfilePatch");
+    assert_eq!(frames[0]["data"]["changed_fields"]["bio"], "Sent through A");
+}
+
+#[tokio::test]
+async fn in_process_relays_leave_other_instances_events_alone() {
+    use shared::types::WsEvent;
+
+    let db = common::TempDb::new();
+    let config = || api::config::Config {
+        database_url: db.url(),
+        ..common::test_config()
+    };
+    let a = common::TestApp::with_config(config()).await;
+
+    // A second instance on the same file, whose relay hasn't run yet
+    let pool = db::pool::create_pool(&db.url()).await.unwrap();
+    let b = api::state::AppState::new(config(), pool).unwrap();
+    b.events
+        .publish(WsEvent::Removed { user_id: 42 })
+        .await
+        .unwrap();
+
+    // Only B's connections are on B's bus, so the event is B's to relay
+    assert_eq!(a.state().events.relay_pending().await.unwrap(), 0);
+    assert_eq!(b.events.relay_pending().await.unwrap(), 1);
+}
+
+/// Needs a Postgres to share; set `APP__TEST_POSTGRES_URL` to run it, e.g.
+/// `postgres://postgres@localhost/rustcard_test`
+#[tokio::test]
+async fn postgres_bus_shares_events_between_instances() {
+    let Ok(postgres_url) = std::env::var("APP__TEST_POSTGRES_URL") else {
+        eprintln!("APP__TEST_POSTGRES_URL is not set; skipping");
+        return;
+    };
+    let db = common::TempDb::new();
+    let config = || api::config::Config {
+        database_url: db.url(),
+        event_bus: api::config::EventBusKind::Postgres,
+        event_bus_postgres_url: Some(postgres_url.clone()),
+        ..common::test_config()
+    };
+    let mut a = common::TestApp::with_config(config()).await;
+    let mut b = common::TestApp::with_config(config()).await;
+    let profile_id = register_profile(&mut a, "pg-bus-a@example.com", "Bus A").await;
+    let other_id = register_profile(&mut b, "pg-bus-b@example.com", "Bus B").await;
+    assert_ne!(profile_id, other_id);
+
+    let addr = b.serve().await;
+    let mut ws = b.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    a.patch(
+        &format!("/api/profiles/{}", profile_id),
+        json!({ "bio": "Sent through Postgres" }),
+    )
+    .await
+    .assert_ok();
+
+    // B's own registration may still be on its way through the bus
+    let patch = loop {
+        let frames = common::recv_frames(&mut ws).await;
+        assert!(!frames.is_empty(), "the patch never arrived");
+        if let Some(patch) = frames.into_iter().find(|f| f["type"] == "ProfilePatch") {
+            break patch;
+        }
+    };
+    assert_eq!(patch["data"]["id"], profile_id);
+    assert_eq!(
+        patch["data"]["changed_fields"]["bio"],
+        "Sent through Postgres"
+    );
+    drop(ws);
+
+    // As if the relay died before marking anything delivered: publishing
+    // it all again appends nothing
+    sqlx::query("UPDATE events SET delivered_at = NULL")
+        .execute(&a.state().db)
+        .await
+        .unwrap();
+    a.state().events.relay_pending().await.unwrap();
+
+    // Resuming replays from Postgres
+    let seq = patch["seq"].as_i64().unwrap();
+    let mut ws = b
+        .connect_ws(
+            addr,
+            &format!("/api/ws?topics=profiles:*&since={}", seq - 1),
+        )
+        .await;
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames, vec![patch]);
+}
+
+/// Update a profile with large bios until well past what the kernel will
+/// buffer for a client that isn't reading. Returns the last bio.
+async fn flood_profile_updates(app: &common::TestApp, profile_id: i64) -> String {
+    let mut bio = String::new();
+    for i in 0..120 {
+        bio = format!("{i}:{}", "x".repeat(128 * 1024));
+        app.patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({ "bio": bio }),
+        )
+        .await
+        .assert_ok();
+    }
+    bio
+}
+
+/// Current value of a metric from `GET /metrics`
+async fn metric(app: &common::TestApp, name: &str) -> i64 {
+    let bearer = format!("Bearer {}", common::METRICS_TOKEN);
+    let response = app
+        .get_with_headers("/metrics", &[("Authorization", &bearer)])
+        .await;
+    response.assert_ok();
+    response
+        .body
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{name} ")))
+        .unwrap_or_else(|| panic!("no metric {name}"))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn slow_websocket_is_disconnected_when_queue_overflows() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_outbound_queue_capacity: 2,
+        ws_overflow_policy: api::config::OverflowPolicy::Disconnect,
+        ..common::test_config()
+    })
+    .await;
+    let profile_id = register_profile(&mut app, "slow@example.com", "Slow").await;
+    let addr = app.serve().await;
+
+    // Not reading: frames back up in the kernel, then in the queue
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    flood_profile_updates(&app, profile_id).await;
+
+    // 4005 = outbound queue full
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(10)).await;
+    assert_eq!(code, Some(4005));
+    assert_eq!(
+        metric(&app, "ws_outbound_overflow_disconnects_total").await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn slow_websocket_gets_coalesced_updates() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_outbound_queue_capacity: 2,
+        ws_overflow_policy: api::config::OverflowPolicy::Coalesce,
+        ..common::test_config()
+    })
+    .await;
+    let profile_id = register_profile(&mut app, "coalesce@example.com", "Coalesce").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    let last_bio = flood_profile_updates(&app, profile_id).await;
+    assert!(metric(&app, "ws_outbound_coalesced_total").await > 0);
+
+    // Some updates were skipped, but the socket stays open and ends up on
+    // the latest state
+    let frames = common::recv_frames(&mut ws).await;
+    assert!(frames.len() < 121);
+    assert_eq!(
+        frames.last().unwrap()["data"]["changed_fields"]["bio"],
+        last_bio
+    );
+    assert_eq!(metric(&app, "ws_outbound_queue_depth").await, 0);
+}
+
+#[tokio::test]
+async fn coalesced_updates_keep_every_change() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_outbound_queue_capacity: 2,
+        ws_overflow_policy: api::config::OverflowPolicy::Coalesce,
+        ..common::test_config()
+    })
+    .await;
+    let profile_id = register_profile(&mut app, "merge@example.com", "Merge").await;
+    let addr = app.serve().await;
+
+    // A rename in the middle of a flood of bio changes, while the queue is
+    // full
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    let mut last_bio = String::new();
+    for i in 0..120 {
+        let body = if i == 60 {
+            json!({ "display_name": "Renamed" })
+        } else {
+            last_bio = format!("{i}:{}", "x".repeat(128 * 1024));
+            json!({ "bio": last_bio })
+        };
+        app.patch(&format!("/api/profiles/{}", profile_id), body)
+            .await
+            .assert_ok();
+    }
+    assert!(metric(&app, "ws_outbound_coalesced_total").await > 0);
+
+    // Every patch applies to the version before it, and together they
+    // carry every change
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames[0]["type"], "Snapshot");
+    let mut profile = frames[0]["data"]["profiles"][0].clone();
+    for frame in &frames[1..] {
+        assert_eq!(frame["type"], "ProfilePatch");
+        let patch = &frame["data"];
+        assert_eq!(patch["base_version"], profile["version"]);
+        profile["version"] = patch["version"].clone();
+        for (field, value) in patch["changed_fields"].as_object().unwrap() {
+            profile[field] = value.clone();
+        }
+    }
+    assert_eq!(profile["version"], 121);
+    assert_eq!(profile["display_name"], "Renamed");
+    assert_eq!(profile["bio"], last_bio);
+}
+
+#[tokio::test]
+async fn drop_oldest_keeps_snapshots_and_replies() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_outbound_queue_capacity: 4,
+        ws_overflow_policy: api::config::OverflowPolicy::DropOldest,
+        ..common::test_config()
+    })
+    .await;
+    let profile_id = register_profile(&mut app, "oldest@example.com", "Oldest").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    flood_profile_updates(&app, profile_id).await;
+
+    // With the queue full, subscribe, then push more updates through
+    let request = json!({
+        "request_id": "s1",
+        "type": "Subscribe",
+        "data": { "topics": [format!("profile:{profile_id}")] }
+    });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for i in 0..10 {
+        app.patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({ "bio": format!("after {i}") }),
+        )
+        .await
+        .assert_ok();
+    }
+    assert!(metric(&app, "ws_outbound_dropped_total").await > 0);
+
+    let frames = common::recv_frames(&mut ws).await;
+    let snapshots = frames.iter().filter(|f| f["type"] == "Snapshot").count();
+    assert_eq!(snapshots, 2);
+    assert!(frames
+        .iter()
+        .any(|f| f["request_id"] == "s1" && f["type"] == "Subscribed"));
+    assert_eq!(
+        frames.last().unwrap()["data"]["changed_fields"]["bio"],
+        "after 9"
+    );
+}
+
+#[tokio::test]
+async fn rapid_profile_updates_are_coalesced() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_coalesce_window_ms: 150,
+        ..common::test_config()
+    })
+    .await;
+    let other_id = register_profile(&mut app, "coalesce-b@example.com", "Other").await;
+    let other = app.cookies();
+    let profile_id = register_profile(&mut app, "coalesce-a@example.com", "Chatty").await;
+    let addr = app.serve().await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    for bio in ["a", "ab", "abc", "abcd"] {
+        app.patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({ "bio": bio }),
+        )
+        .await
+        .assert_ok();
+    }
+    app.set_cookies(other);
+    app.patch(
+        &format!("/api/profiles/{}", other_id),
+        json!({ "bio": "Separate" }),
+    )
+    .await
+    .assert_ok();
+
+    // One patch per profile, carrying its latest state
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 2);
+    let bio_of = |id: i64| {
+        frames
+            .iter()
+            .find(|f| f["data"]["id"] == id)
+            .map(|f| f["data"]["changed_fields"]["bio"].clone())
+    };
+    assert_eq!(bio_of(profile_id), Some(json!("abcd")));
+    assert_eq!(bio_of(other_id), Some(json!("Separate")));
+
+    // The merged patch spans all four updates
+    let merged = frames
+        .iter()
+        .find(|f| f["data"]["id"] == profile_id)
+        .unwrap();
+    assert_eq!(merged["data"]["base_version"], 1);
+    assert_eq!(merged["data"]["version"], 5);
+}
+
+#[tokio::test]
+async fn coalescing_holds_back_only_patches() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_coalesce_window_ms: 5000,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "watcher@example.com", "Watcher").await;
+    let addr = app.serve().await;
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    common::recv_frames(&mut ws).await;
+
+    // A new profile has nothing to merge with, so it doesn't wait
+    register_profile(&mut app, "prompt@example.com", "Prompt").await;
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "Profile");
+}
+
+#[tokio::test]
+async fn merged_patches_cover_the_events_they_replace() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_coalesce_window_ms: 150,
+        ..common::test_config()
+    })
+    .await;
+    let profile_id = register_profile(&mut app, "cover@example.com", "Cover").await;
+    let mut events_rx = app.state().subscribe_events();
+    let start = app.state().events.head().await.unwrap();
+
+    for bio in ["a", "ab", "abc"] {
+        app.patch(
+            &format!("/api/profiles/{}", profile_id),
+            json!({ "bio": bio }),
+        )
+        .await
+        .assert_ok();
+    }
+    let end = app.state().events.head().await.unwrap();
+
+    // Each event picks up where the one before left off, so subscribers
+    // never have to read the log to fill in what was merged away
+    let mut cursor = start;
+    while cursor < end {
+        let published = tokio::time::timeout(Duration::from_secs(5), events_rx.recv())
+            .await
+            .expect("the patches never arrived")
+            .unwrap();
+        // The sign-up may still have been on its way
+        if published.event.seq <= start {
+            continue;
+        }
+        assert_eq!(published.after, cursor);
+        cursor = published.event.seq;
+    }
+    assert_eq!(cursor, end);
+}
+
+#[tokio::test]
+async fn websocket_msgpack_subprotocol() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::new().await;
+    let profile_id = register_profile(&mut app, "msgpack@example.com", "Packed").await;
+    let addr = app.serve().await;
+
+    let (mut ws, selected) = app
+        .connect_ws_with_protocol(addr, "/api/ws?topics=profiles:*", "msgpack")
+        .await;
+    assert_eq!(selected.as_deref(), Some("msgpack"));
+
+    // Same shape as the JSON frames, just binary
+    let frames = common::recv_msgpack_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "Snapshot");
+    assert_eq!(frames[0]["data"]["profiles"][0]["display_name"], "Packed");
+
+    // Requests can be MessagePack too
+    let request = json!({
+        "request_id": "m1",
+        "type": "UpdateProfile",
+        "data": { "id": profile_id, "bio": "Binary bio" }
+    });
+    let bytes = rmp_serde::to_vec_named(&request).unwrap();
+    ws.send(Message::Binary(bytes.into())).await.unwrap();
+
+    let frames = common::recv_msgpack_frames(&mut ws).await;
+    let reply = frames
+        .iter()
+        .find(|f| f["request_id"] == "m1")
+        .expect("no reply");
+    assert_eq!(reply["type"], "ProfileUpdated");
+    assert!(
+        frames
+            .iter()
+            .any(|f| f["type"] == "ProfilePatch"
+                && f["data"]["changed_fields"]["bio"] == "Binary bio")
+    );
+}
+
+#[tokio::test]
+async fn websocket_msgpack_query_param() {
+    let mut app = common::TestApp::new().await;
+    register_profile(&mut app, "msgpack-query@example.com", "Query Packed").await;
+    let addr = app.serve().await;
+
+    let mut ws = app
+        .connect_ws(addr, "/api/ws?topics=profiles:*&encoding=msgpack")
+        .await;
+    let frames = common::recv_msgpack_frames(&mut ws).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0]["type"], "Snapshot");
+
+    // JSON stays the default
+    let mut ws = app.connect_ws(addr, "/api/ws?topics=profiles:*").await;
+    assert_eq!(common::recv_frames(&mut ws).await.len(), 1);
+
+    let sse = app.open_sse("/api/events?encoding=msgpack", None).await;
+    assert_eq!(sse.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn per_user_connection_cap() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_connections_per_user: 2,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "capped@example.com", "Capped").await;
+    let addr = app.serve().await;
+
+    let first = app.connect_ws(addr, "/api/ws").await;
+    let _second = app.connect_ws(addr, "/api/ws").await;
+    let err = app.try_connect_ws(addr, "/api/ws").await.unwrap_err();
+    assert_eq!(upgrade_status(err), 429);
+    // Server-sent event streams count too
+    let sse = app.open_sse("/api/events", None).await;
+    assert_eq!(sse.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        metric(&app, r#"ws_rejected_connections_total{limit="per_user"}"#).await,
+        2
+    );
+    assert_eq!(metric(&app, "ws_connections").await, 2);
+
+    // Closing a socket frees its slot
+    drop(first);
+    let mut reopened = None;
+    for _ in 0..20 {
+        if let Ok(ws) = app.try_connect_ws(addr, "/api/ws").await {
+            reopened = Some(ws);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reopened.is_some());
+}
+
+#[tokio::test]
+async fn per_ip_connection_cap() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_connections_per_ip: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "ip-a@example.com", "A").await;
+    let a = app.cookies();
+    register_profile(&mut app, "ip-b@example.com", "B").await;
+    let addr = app.serve().await;
+
+    // Different users, same address
+    let _ws = app.connect_ws(addr, "/api/ws").await;
+    app.set_cookies(a);
+    let err = app.try_connect_ws(addr, "/api/ws").await.unwrap_err();
+    assert_eq!(upgrade_status(err), 429);
+    assert_eq!(
+        metric(&app, r#"ws_rejected_connections_total{limit="per_ip"}"#).await,
+        1
+    );
+}
+
+/// Open `/api/ws` as if through a proxy that added `forwarded_for`
+async fn connect_forwarded_for(
+    app: &common::TestApp,
+    addr: SocketAddr,
+    forwarded_for: &str,
+) -> Result<common::WsClient, tokio_tungstenite::tungstenite::Error> {
+    app.try_connect_ws_with_headers(addr, "/api/ws", &[("X-Forwarded-For", forwarded_for)])
+        .await
+}
+
+#[tokio::test]
+async fn global_connection_cap() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_connections: 2,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "global-a@example.com", "A").await;
+    let a = app.cookies();
+    register_profile(&mut app, "global-b@example.com", "B").await;
+    let addr = app.serve().await;
+
+    // Under the per-user and per-IP caps, but not the total
+    let first = app.connect_ws(addr, "/api/ws").await;
+    app.set_cookies(a);
+    let _second = app.connect_ws(addr, "/api/ws").await;
+    let err = app.try_connect_ws(addr, "/api/ws").await.unwrap_err();
+    assert_eq!(upgrade_status(err), 429);
+    let sse = app.open_sse("/api/events", None).await;
+    assert_eq!(sse.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        metric(&app, r#"ws_rejected_connections_total{limit="global"}"#).await,
+        2
+    );
+
+    drop(first);
+    let mut reopened = None;
+    for _ in 0..20 {
+        if let Ok(ws) = app.try_connect_ws(addr, "/api/ws").await {
+            reopened = Some(ws);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reopened.is_some());
+}
+
+#[tokio::test]
+async fn per_ip_cap_believes_trusted_proxies() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_connections_per_ip: 1,
+        trusted_proxies: Some("127.0.0.1, 10.0.0.1".to_string()),
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "proxied@example.com", "Proxied").await;
+    let addr = app.serve().await;
+
+    // Two clients behind the same proxy
+    let _first = connect_forwarded_for(&app, addr, "203.0.113.1")
+        .await
+        .unwrap();
+    let _second = connect_forwarded_for(&app, addr, "203.0.113.2")
+        .await
+        .unwrap();
+    // Only the entries trusted proxies added count; the client's own claim
+    // before them doesn't
+    let err = connect_forwarded_for(&app, addr, "198.51.100.7, 203.0.113.1, 10.0.0.1")
+        .await
+        .unwrap_err();
+    assert_eq!(upgrade_status(err), 429);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn per_ip_cap_believes_a_proxy_on_a_unix_socket() {
+    use api::config::ListenAddr;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_connections_per_ip: 1,
+        trusted_proxies: Some("unix".to_string()),
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "unix-proxy@example.com", "Unix Proxy").await;
+    let path = std::env::temp_dir().join(format!("api-test-{}.sock", rand::random::<u64>()));
+    let listeners = api::server::bind(&[ListenAddr::Unix(path.clone())])
+        .await
+        .unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(api::server::serve(
+        listeners,
+        app.router(),
+        app.state().clone(),
+        async {
+            stopped.await.ok();
+        },
+    ));
+
+    let connect = |forwarded_for: &str| {
+        let mut req = "ws://localhost/api/ws".into_client_request().unwrap();
+        req.headers_mut()
+            .insert("Cookie", app.cookies().unwrap().parse().unwrap());
+        req.headers_mut()
+            .insert("X-Forwarded-For", forwarded_for.parse().unwrap());
+        let path = path.clone();
+        async move {
+            let stream = tokio::net::UnixStream::connect(path).await.unwrap();
+            tokio_tungstenite::client_async(req, stream).await
+        }
+    };
+    let first = connect("203.0.113.1").await.unwrap();
+    let second = connect("203.0.113.2").await.unwrap();
+    let err = connect("203.0.113.1").await.unwrap_err();
+    assert_eq!(upgrade_status(err), 429);
+
+    drop((first, second));
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn per_ip_cap_ignores_forwarded_for_from_untrusted_peers() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        ws_max_connections_per_ip: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "spoofed@example.com", "Spoofed").await;
+    let addr = app.serve().await;
+
+    let _ws = connect_forwarded_for(&app, addr, "203.0.113.1")
+        .await
+        .unwrap();
+    let err = connect_forwarded_for(&app, addr, "203.0.113.2")
+        .await
+        .unwrap_err();
+    assert_eq!(upgrade_status(err), 429);
+}
+
+#[tokio::test]
+async fn metrics_require_the_token() {
+    let app = common::TestApp::new().await;
+    let response = app.get("/metrics").await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+    let response = app
+        .get_with_headers("/metrics", &[("Authorization", "Bearer wrong")])
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Not served at all without a token configured
+    let app = common::TestApp::with_config(api::config::Config {
+        metrics_token: None,
+        ..common::test_config()
+    })
+    .await;
+    let bearer = format!("Bearer {}", common::METRICS_TOKEN);
+    let response = app
+        .get_with_headers("/metrics", &[("Authorization", &bearer)])
+        .await;
+    response.assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn graceful_shutdown_closes_websockets() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        shutdown_grace_period_secs: 5,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "restart@example.com", "Restart").await;
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let (addr, server) = app
+        .serve_until(async {
+            stopped.await.ok();
+        })
+        .await;
+
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+    common::recv_frames(&mut ws).await;
+    assert_eq!(metric(&app, "ws_connections").await, 1);
+
+    stop.send(()).unwrap();
+    assert_eq!(
+        common::recv_close_code(&mut ws, Duration::from_secs(2)).await,
+        Some(1012)
+    );
+    drop(ws);
+
+    // Shutdown finishes well within the grace period once the socket is gone
+    tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .expect("shutdown didn't finish")
+        .unwrap()
+        .unwrap();
+    assert!(app.state().db.is_closed());
+    assert!(app.try_connect_ws(addr, "/api/ws").await.is_err());
+}
+
+#[test]
+fn listen_addresses() {
+    use api::config::{Config, ListenAddr};
+
+    let tcp = |host: &str, port| ListenAddr::Tcp {
+        host: host.to_string(),
+        port,
+    };
+
+    // `host` and `port` unless `listen` is set
+    let config = Config {
+        host: "::1".to_string(),
+        port: 8080,
+        ..common::test_config()
+    };
+    assert_eq!(config.listen_addrs().unwrap(), vec![tcp("::1", 8080)]);
+
+    let config = Config {
+        listen: Some("127.0.0.1:80, [::]:443,localhost:3000,unix:/run/api.sock".to_string()),
+        ..common::test_config()
+    };
+    assert_eq!(
+        config.listen_addrs().unwrap(),
+        vec![
+            tcp("127.0.0.1", 80),
+            tcp("::", 443),
+            tcp("localhost", 3000),
+            ListenAddr::Unix("/run/api.sock".into()),
+        ]
+    );
+
+    for invalid in [
+        "localhost",
+        "::1:80",
+        "[::1:80",
+        ":80",
+        "unix:",
+        "host:http",
+        "",
+    ] {
+        let config = Config {
+            listen: Some(invalid.to_string()),
+            ..common::test_config()
+        };
+        assert!(
+            config.listen_addrs().is_err(),
+            "{invalid:?} should be rejected"
+        );
+    }
+}
+
+#[test]
+fn config_rejects_zero_capacities_and_intervals() {
+    use api::config::Config;
+
+    assert!(common::test_config().validate().is_ok());
+
+    let invalid = [
+        Config {
+            event_channel_capacity: 0,
+            ..common::test_config()
+        },
+        Config {
+            ws_outbound_queue_capacity: 0,
+            ..common::test_config()
+        },
+        Config {
+            ws_ping_interval_secs: 0,
+            ..common::test_config()
+        },
+        Config {
+            ws_pong_timeout_secs: 0,
+            ..common::test_config()
+        },
+        Config {
+            ws_max_lifetime_secs: 0,
+            ..common::test_config()
+        },
+        Config {
+            session_sweep_interval_secs: 0,
+            ..common::test_config()
+        },
+        Config {
+            event_log_compact_interval_secs: 0,
+            ..common::test_config()
+        },
+        Config {
+            event_relay_poll_interval_ms: 0,
+            ..common::test_config()
+        },
+        Config {
+            event_bus_poll_interval_ms: 0,
+            ..common::test_config()
+        },
+        Config {
+            tls_reload_interval_secs: 0,
+            ..common::test_config()
+        },
+    ];
+    for config in invalid {
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().ends_with("must be greater than 0"));
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serves_on_a_unix_socket() {
+    use api::config::ListenAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let app = common::TestApp::new().await;
+    let path = std::env::temp_dir().join(format!("api-test-{}.sock", rand::random::<u64>()));
+    let listeners = api::server::bind(&[ListenAddr::Unix(path.clone())])
+        .await
+        .unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(api::server::serve(
+        listeners,
+        app.router(),
+        app.state().clone(),
+        async {
+            stopped.await.ok();
+        },
+    ));
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    // The socket file is cleaned up on shutdown
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+/// Serve `app` over TLS with `cert` on an ephemeral port, plus an HTTP
+/// listener redirecting to it
+async fn serve_tls(
+    app: &common::TestApp,
+    cert: &common::TestCert,
+) -> (api::tls::Certificates, SocketAddr, SocketAddr) {
+    use api::{config::ListenAddr, server};
+
+    let local = [ListenAddr::Tcp {
+        host: "127.0.0.1".to_string(),
+        port: 0,
+    }];
+    let certificates = api::tls::Certificates::load(&cert.cert_path, &cert.key_path).unwrap();
+    let mut listeners = server::secure(server::bind(&local).await.unwrap(), &certificates).unwrap();
+    let https_port = server::https_port(&listeners).unwrap();
+    let redirects = server::bind_redirects(&local, https_port).await.unwrap();
+    let http_port = match &redirects[0] {
+        server::Listener::Redirect { listener, .. } => listener.local_addr().unwrap().port(),
+        _ => unreachable!(),
+    };
+    listeners.extend(redirects);
+
+    tokio::spawn(server::serve(
+        listeners,
+        app.router(),
+        app.state().clone(),
+        std::future::pending(),
+    ));
+    (
+        certificates,
+        SocketAddr::from(([127, 0, 0, 1], https_port)),
+        SocketAddr::from(([127, 0, 0, 1], http_port)),
+    )
+}
+
+#[tokio::test]
+async fn serves_https_and_wss() {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let mut app = common::TestApp::new().await;
+    register_profile(&mut app, "tls@example.com", "Secure").await;
+    let cert = common::TestCert::new();
+    let (_certificates, https, _) = serve_tls(&app, &cert).await;
+
+    let mut stream = common::tls_connect(https, &cert.der).await.unwrap();
+    let response = common::raw_request(
+        &mut stream,
+        "GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    // WebSockets work over the same listener
+    let stream = common::tls_connect(https, &cert.der).await.unwrap();
+    let mut req = format!("wss://localhost:{}/api/ws?topics=profiles:*", https.port())
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Cookie", app.cookies().unwrap().parse().unwrap());
+    let (mut ws, _) = tokio_tungstenite::client_async(req, stream).await.unwrap();
+    let frames = common::recv_frames(&mut ws).await;
+    assert_eq!(frames[0]["type"], "Snapshot");
+}
+
+#[tokio::test]
+async fn https_clients_offering_h2_get_http1() {
+    let app = common::TestApp::new().await;
+    let cert = common::TestCert::new();
+    let (_certificates, https, _) = serve_tls(&app, &cert).await;
+
+    let mut stream = common::tls_connect_with_alpn(https, &cert.der, &[b"h2", b"http/1.1"])
+        .await
+        .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    let response = common::raw_request(
+        &mut stream,
+        "GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
+
+#[tokio::test]
+async fn renewed_certificates_are_served_without_a_restart() {
+    let app = common::TestApp::new().await;
+    let mut cert = common::TestCert::new();
+    let (certificates, https, _) = serve_tls(&app, &cert).await;
+    let old = cert.der.clone();
+    assert!(common::tls_connect(https, &old).await.is_ok());
+
+    // Nothing changed yet
+    assert!(!certificates.reload_if_changed().unwrap());
+
+    cert.renew();
+    assert!(certificates.reload_if_changed().unwrap());
+    assert!(common::tls_connect(https, &cert.der).await.is_ok());
+    assert!(common::tls_connect(https, &old).await.is_err());
+
+    // A broken renewal keeps the working certificate in service
+    std::fs::write(&cert.key_path, "not a key").unwrap();
+    assert!(certificates.reload_if_changed().is_err());
+    assert!(common::tls_connect(https, &cert.der).await.is_ok());
+}
+
+#[tokio::test]
+async fn plain_http_is_redirected_to_https() {
+    let app = common::TestApp::new().await;
+    let cert = common::TestCert::new();
+    let (_certificates, https, http) = serve_tls(&app, &cert).await;
+
+    let mut stream = tokio::net::TcpStream::connect(http).await.unwrap();
+    let response = common::raw_request(
+        &mut stream,
+        &format!(
+            "POST /api/auth/login?next=%2F HTTP/1.1\r\nHost: localhost:{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            http.port()
+        ),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 308"), "{response}");
+    let location = format!(
+        "location: https://localhost:{}/api/auth/login?next=%2F",
+        https.port()
+    );
+    assert!(
+        response.to_lowercase().contains(&location.to_lowercase()),
+        "{response}"
+    );
+}
+
+#[tokio::test]
+async fn logout_on_one_instance_closes_websockets_on_another() {
+    let db = common::TempDb::new();
+    let config = || api::config::Config {
+        database_url: db.url(),
+        event_bus: api::config::EventBusKind::Polling,
+        event_bus_poll_interval_ms: 50,
+        ..common::test_config()
+    };
+    let mut a = common::TestApp::with_config(config()).await;
+    let mut b = common::TestApp::with_config(config()).await;
+    register_profile(&mut a, "bus-logout@example.com", "Bus").await;
+
+    b.set_cookies(a.cookies());
+    let addr = b.serve().await;
+    let mut ws = b.connect_ws(addr, "/api/ws").await;
+
+    a.post("/api/auth/logout", json!({})).await.assert_ok();
+
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(5)).await;
+    assert_eq!(code, Some(4001));
+}
+
+#[tokio::test]
+async fn revocation_burst_past_channel_capacity_closes_websocket() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        event_channel_capacity: 2,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "burst@example.com", "Burst").await;
+    let first = app.cookies();
+    for _ in 0..5 {
+        app.post(
+            "/api/auth/login",
+            json!({ "email": "burst@example.com", "password": "password123" }),
+        )
+        .await
+        .assert_ok();
+    }
+    let addr = app.serve().await;
+
+    let last = app.cookies();
+    app.set_cookies(first);
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+
+    // Five revocations at once overrun the socket's receiver, which has to
+    // find out about its own from the database
+    app.set_cookies(last);
+    let response = app.post("/api/auth/logout-all", json!({})).await;
+    assert_eq!(response.json()["revoked"], 5);
+
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(5)).await;
+    assert_eq!(code, Some(4001));
+}
+
+#[tokio::test]
+async fn websocket_commands_check_the_session_first() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut app = common::TestApp::new().await;
+    let profile_id = register_profile(&mut app, "commands@example.com", "Commands").await;
+    let addr = app.serve().await;
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+
+    // Gone from the database, with no revocation on its way to the socket
+    sqlx::query("DELETE FROM sessions")
+        .execute(&app.state().db)
+        .await
+        .unwrap();
+
+    let request = json!({
+        "request_id": "s1",
+        "type": "Subscribe",
+        "data": { "topics": [format!("profile:{profile_id}")] }
+    });
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .unwrap();
+
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(5)).await;
+    assert_eq!(code, Some(4001));
+}
+
+#[tokio::test]
+async fn sweeping_an_expired_session_closes_its_websocket() {
+    let mut app = common::TestApp::with_config(api::config::Config {
+        session_idle_timeout_secs: 2,
+        session_sweep_interval_secs: 1,
+        ..common::test_config()
+    })
+    .await;
+    register_profile(&mut app, "swept@example.com", "Swept").await;
+    let addr = app.serve().await;
+    let mut ws = app.connect_ws(addr, "/api/ws").await;
+
+    // An open socket doesn't keep its session alive. SQLite timestamps have
+    // one-second resolution, so wait until the session is surely idle for
+    // long enough, then let the sweeper delete it.
+    tokio::time::sleep(Duration::from_millis(3100)).await;
+    let sweeper = api::tasks::spawn_session_sweeper(app.state().clone());
+    let code = common::recv_close_code(&mut ws, Duration::from_secs(3)).await;
+    sweeper.abort();
+    assert_eq!(code, Some(4001));
+}
@@ -1,25 +1,219 @@
-use api::{config::Config, routes, state::AppState};
+use api::{config::Config, routes, server, state::AppState, tasks};
 use axum::{
     body::Body,
     http::{Request, StatusCode},
     Router,
 };
 use http_body_util::BodyExt;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest},
+    MaybeTlsStream, WebSocketStream,
+};
 use tower::ServiceExt;
 
+pub type WsClient = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Collect JSON text frames until the socket goes quiet
+pub async fn recv_frames<S>(ws: &mut WebSocketStream<S>) -> Vec<serde_json::Value>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut frames = Vec::new();
+    while let Ok(Some(Ok(msg))) =
+        tokio::time::timeout(std::time::Duration::from_millis(300), ws.next()).await
+    {
+        if let Message::Text(text) = msg {
+            frames.push(serde_json::from_str(&text).unwrap());
+        }
+    }
+    frames
+}
+
+/// Collect MessagePack binary frames, decoded to JSON values, until the
+/// socket goes quiet
+pub async fn recv_msgpack_frames(ws: &mut WsClient) -> Vec<serde_json::Value> {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut frames = Vec::new();
+    while let Ok(Some(Ok(msg))) =
+        tokio::time::timeout(std::time::Duration::from_millis(300), ws.next()).await
+    {
+        match msg {
+            Message::Binary(bytes) => frames.push(rmp_serde::from_slice(&bytes).unwrap()),
+            Message::Text(text) => panic!("unexpected text frame: {text}"),
+            _ => {}
+        }
+    }
+    frames
+}
+
+/// Read until the server closes the socket and return the close code, or
+/// `None` if it's still open after `within`
+pub async fn recv_close_code(ws: &mut WsClient, within: std::time::Duration) -> Option<u16> {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    tokio::time::timeout(within, async {
+        while let Some(msg) = ws.next().await {
+            if let Ok(Message::Close(frame)) = msg {
+                return frame.map(|f| u16::from(f.code));
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// A SQLite database file that is deleted when dropped.
+pub struct TempDb {
+    path: PathBuf,
+}
+
+impl TempDb {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("api-test-{}.db", rand::random::<u64>()));
+        Self { path }
+    }
+
+    pub fn url(&self) -> String {
+        format!("sqlite:{}", self.path.display())
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// A self-signed certificate for `localhost` in temporary PEM files, which
+/// are deleted when dropped.
+pub struct TestCert {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub der: CertificateDer<'static>,
+}
+
+impl TestCert {
+    pub fn new() -> Self {
+        let stem = std::env::temp_dir().join(format!("api-test-{}", rand::random::<u64>()));
+        let mut cert = Self {
+            cert_path: stem.with_extension("crt"),
+            key_path: stem.with_extension("key"),
+            der: CertificateDer::from(Vec::new()),
+        };
+        cert.renew();
+        cert
+    }
+
+    /// Overwrite the files with a newly generated certificate and key
+    pub fn renew(&mut self) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&self.cert_path, generated.cert.pem()).unwrap();
+        std::fs::write(&self.key_path, generated.signing_key.serialize_pem()).unwrap();
+        self.der = generated.cert.der().clone();
+    }
+}
+
+impl Drop for TestCert {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.cert_path).ok();
+        std::fs::remove_file(&self.key_path).ok();
+    }
+}
+
+/// Open a TLS connection to `addr` as `localhost`, trusting only `cert`
+pub async fn tls_connect(
+    addr: SocketAddr,
+    cert: &CertificateDer<'static>,
+) -> std::io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+    tls_connect_with_alpn(addr, cert, &[]).await
+}
+
+/// Like [`tls_connect`], offering `alpn` protocols in order of preference
+pub async fn tls_connect_with_alpn(
+    addr: SocketAddr,
+    cert: &CertificateDer<'static>,
+    alpn: &[&[u8]],
+) -> std::io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone()).unwrap();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+}
+
+/// Send a bodiless HTTP/1.1 request over `stream` and return the raw response
+pub async fn raw_request<S>(stream: &mut S, request: &str) -> String
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.ok();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// What tests scrape `/metrics` with
+pub const METRICS_TOKEN: &str = "test-metrics-token";
+
+/// Baseline config for tests; override fields with struct update syntax.
+pub fn test_config() -> Config {
+    Config {
+        port: 0,
+        host: "127.0.0.1".to_string(),
+        database_url: "sqlite::memory:".to_string(),
+        metrics_token: Some(METRICS_TOKEN.to_string()),
+        ..Config::default()
+    }
+}
+
 pub struct TestApp {
     app: Router,
+    state: AppState,
     cookies: Option<String>,
 }
 
 impl TestApp {
     pub async fn new() -> Self {
-        let config = Config {
-            port: 0,
-            host: "127.0.0.1".to_string(),
-            database_url: "sqlite::memory:".to_string(),
-        };
+        Self::with_database("sqlite::memory:").await
+    }
+
+    /// Build an app against a specific database, e.g. to simulate a restart
+    /// by pointing two apps at the same file.
+    pub async fn with_database(database_url: &str) -> Self {
+        Self::with_config(Config {
+            database_url: database_url.to_string(),
+            ..test_config()
+        })
+        .await
+    }
 
+    pub async fn with_config(config: Config) -> Self {
         let pool = db::pool::create_pool(&config.database_url)
             .await
             .expect("Failed to create test pool");
@@ -28,13 +222,65 @@ impl TestApp {
             .await
             .expect("Failed to run migrations");
 
-        let state = AppState::new(config, pool);
-        let app = routes::router(state);
+        let state = AppState::new(config, pool).expect("Failed to build app state");
+        tasks::spawn_event_relay(state.clone());
+        let app = routes::router(state.clone());
 
-        Self { app, cookies: None }
+        Self {
+            app,
+            state,
+            cookies: None,
+        }
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    pub fn router(&self) -> Router {
+        self.app.clone()
+    }
+
+    pub fn cookies(&self) -> Option<String> {
+        self.cookies.clone()
+    }
+
+    pub fn set_cookies(&mut self, cookies: Option<String>) {
+        self.cookies = cookies;
+    }
+
+    /// Open a server-sent event stream, resuming from `last_event_id`
+    pub async fn open_sse(&self, uri: &str, last_event_id: Option<i64>) -> SseClient {
+        let mut req = Request::builder()
+            .method("GET")
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+
+        if let Some(ref cookies) = self.cookies {
+            req.headers_mut().insert("Cookie", cookies.parse().unwrap());
+        }
+        if let Some(id) = last_event_id {
+            req.headers_mut().insert("Last-Event-ID", id.into());
+        }
+
+        let response = self.app.clone().oneshot(req).await.unwrap();
+        SseClient {
+            status: response.status(),
+            body: response.into_body(),
+            buffer: String::new(),
+        }
     }
 
     pub async fn get(&self, uri: &str) -> TestResponse {
+        self.get_with_headers(uri, &[]).await
+    }
+
+    pub async fn get_with_headers(
+        &self,
+        uri: &str,
+        headers: &[(&'static str, &str)],
+    ) -> TestResponse {
         let mut req = Request::builder()
             .method("GET")
             .uri(uri)
@@ -44,6 +290,9 @@ impl TestApp {
         if let Some(ref cookies) = self.cookies {
             req.headers_mut().insert("Cookie", cookies.parse().unwrap());
         }
+        for (name, value) in headers {
+            req.headers_mut().insert(*name, value.parse().unwrap());
+        }
 
         let response = self.app.clone().oneshot(req).await.unwrap();
         TestResponse::from_response(response).await
@@ -72,6 +321,115 @@ impl TestApp {
         test_response
     }
 
+    pub async fn delete(&mut self, uri: &str) -> TestResponse {
+        let mut req = Request::builder()
+            .method("DELETE")
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+
+        if let Some(ref cookies) = self.cookies {
+            req.headers_mut().insert("Cookie", cookies.parse().unwrap());
+        }
+
+        let response = self.app.clone().oneshot(req).await.unwrap();
+        let test_response = TestResponse::from_response(response).await;
+
+        if let Some(ref cookie) = test_response.set_cookie {
+            self.cookies = Some(cookie.clone());
+        }
+
+        test_response
+    }
+
+    /// Serve the app on an ephemeral port, for tests that need a real socket
+    /// (e.g. WebSockets).
+    pub async fn serve(&self) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = self.app.clone();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        addr
+    }
+
+    /// Like [`TestApp::serve`], but shuts down gracefully once `signal`
+    /// resolves. The returned handle finishes when shutdown is complete.
+    pub async fn serve_until(
+        &self,
+        signal: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> (SocketAddr, tokio::task::JoinHandle<std::io::Result<()>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(server::serve(
+            vec![server::Listener::Tcp(listener)],
+            self.app.clone(),
+            self.state.clone(),
+            signal,
+        ));
+        (addr, handle)
+    }
+
+    /// Open a WebSocket to the served app, sending the current session cookie
+    pub async fn connect_ws(&self, addr: SocketAddr, path: &str) -> WsClient {
+        self.try_connect_ws(addr, path).await.unwrap()
+    }
+
+    pub async fn try_connect_ws(
+        &self,
+        addr: SocketAddr,
+        path: &str,
+    ) -> Result<WsClient, tungstenite::Error> {
+        self.try_connect_ws_with_headers(addr, path, &[]).await
+    }
+
+    pub async fn try_connect_ws_with_headers(
+        &self,
+        addr: SocketAddr,
+        path: &str,
+        headers: &[(&'static str, &str)],
+    ) -> Result<WsClient, tungstenite::Error> {
+        let mut req = format!("ws://{addr}{path}").into_client_request().unwrap();
+        if let Some(ref cookies) = self.cookies {
+            req.headers_mut().insert("Cookie", cookies.parse().unwrap());
+        }
+        for (name, value) in headers {
+            req.headers_mut().insert(*name, value.parse().unwrap());
+        }
+
+        let (ws, _) = tokio_tungstenite::connect_async(req).await?;
+        Ok(ws)
+    }
+
+    /// Connect asking for a WebSocket subprotocol, returning the one the
+    /// server picked
+    pub async fn connect_ws_with_protocol(
+        &self,
+        addr: SocketAddr,
+        path: &str,
+        protocol: &str,
+    ) -> (WsClient, Option<String>) {
+        let mut req = format!("ws://{addr}{path}").into_client_request().unwrap();
+        if let Some(ref cookies) = self.cookies {
+            req.headers_mut().insert("Cookie", cookies.parse().unwrap());
+        }
+        req.headers_mut()
+            .insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+
+        let (ws, response) = tokio_tungstenite::connect_async(req).await.unwrap();
+        let selected = response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .map(|v| v.to_str().unwrap().to_string());
+        (ws, selected)
+    }
+
     pub async fn patch(&self, uri: &str, body: serde_json::Value) -> TestResponse {
         let mut req = Request::builder()
             .method("PATCH")
@@ -89,6 +447,51 @@ impl TestApp {
     }
 }
 
+/// One parsed server-sent event
+pub struct SseEvent {
+    pub id: Option<i64>,
+    pub event: Option<String>,
+    pub data: serde_json::Value,
+}
+
+pub struct SseClient {
+    pub status: StatusCode,
+    body: Body,
+    buffer: String,
+}
+
+impl SseClient {
+    /// Collect events until the stream goes quiet or ends. Keep-alive
+    /// comments are skipped.
+    pub async fn recv_events(&mut self) -> Vec<SseEvent> {
+        while let Ok(Some(Ok(frame))) =
+            tokio::time::timeout(std::time::Duration::from_millis(300), self.body.frame()).await
+        {
+            if let Ok(data) = frame.into_data() {
+                self.buffer.push_str(std::str::from_utf8(&data).unwrap());
+            }
+        }
+
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.find("\n\n") {
+            let block: String = self.buffer.drain(..end + 2).collect();
+            let (mut id, mut event, mut data) = (None, None, None);
+            for line in block.lines() {
+                match line.split_once(':') {
+                    Some(("id", v)) => id = Some(v.trim().parse().unwrap()),
+                    Some(("event", v)) => event = Some(v.trim().to_string()),
+                    Some(("data", v)) => data = Some(serde_json::from_str(v.trim()).unwrap()),
+                    _ => {}
+                }
+            }
+            if let Some(data) = data {
+                events.push(SseEvent { id, event, data });
+            }
+        }
+        events
+    }
+}
+
 pub struct TestResponse {
     pub status: StatusCode,
     pub body: String,
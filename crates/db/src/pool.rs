@@ -17,6 +17,17 @@ pub async fn create_pool(database_url: &str) -> Result<DbPool, sqlx::Error> {
         }
     }
 
+    // Every connection to an in-memory database gets its own empty copy, so
+    // keep exactly one connection alive for the lifetime of the pool
+    if database_url.ends_with(":memory:") {
+        return SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect(database_url)
+            .await;
+    }
+
     SqlitePoolOptions::new()
         .max_connections(5)
         .connect(database_url)
@@ -0,0 +1,128 @@
+use crate::DbPool;
+use sqlx::{FromRow, SqliteExecutor};
+
+#[derive(Debug, FromRow)]
+pub struct EventRow {
+    pub seq: i64,
+    pub payload: String,
+    pub created_at: String,
+}
+
+/// Insert an event into the outbox. Pass a transaction to record it
+/// atomically with the change it describes. `origin` is the process
+/// committing it, whose relay is the one to publish it on a bus that
+/// doesn't reach every process; events without one are anyone's.
+pub async fn append_event(
+    executor: impl SqliteExecutor<'_>,
+    payload: &str,
+    origin: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO events (payload, origin)
+        VALUES (?, ?)
+        "#,
+    )
+    .bind(payload)
+    .bind(origin)
+    .execute(executor)
+    .await?;
+
+    Ok(result.last_insert_rowid())
+}
+
+/// Oldest and newest sequence numbers still in the log, if any
+pub async fn get_event_bounds(pool: &DbPool) -> Result<Option<(i64, i64)>, sqlx::Error> {
+    let (min, max): (Option<i64>, Option<i64>) = sqlx::query_as(
+        r#"
+        SELECT MIN(seq), MAX(seq)
+        FROM events
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(min.zip(max))
+}
+
+pub async fn list_events_since(pool: &DbPool, since: i64) -> Result<Vec<EventRow>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT seq, payload, created_at
+        FROM events
+        WHERE seq > ?
+        ORDER BY seq
+        "#,
+    )
+    .bind(since)
+    .fetch_all(pool)
+    .await
+}
+
+/// Events the relay hasn't broadcast yet, oldest first. With `origin`,
+/// only the ones that process committed (or that have no origin).
+pub async fn list_undelivered_events(
+    pool: &DbPool,
+    origin: Option<&str>,
+) -> Result<Vec<EventRow>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT seq, payload, created_at
+        FROM events
+        WHERE delivered_at IS NULL
+          AND (?1 IS NULL OR origin IS NULL OR origin = ?1)
+        ORDER BY seq
+        "#,
+    )
+    .bind(origin)
+    .fetch_all(pool)
+    .await
+}
+
+/// Mark every event up to `seq` as broadcast; with `origin`, only those
+/// [`list_undelivered_events`] would list for it
+pub async fn mark_events_delivered(
+    executor: impl SqliteExecutor<'_>,
+    seq: i64,
+    origin: Option<&str>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE events
+        SET delivered_at = datetime('now')
+        WHERE seq <= ?1 AND delivered_at IS NULL
+          AND (?2 IS NULL OR origin IS NULL OR origin = ?2)
+        "#,
+    )
+    .bind(seq)
+    .bind(origin)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Delete all but the newest `retain` events (at least one is always kept,
+/// so the head sequence number survives compaction). Events the relay
+/// hasn't broadcast yet are never deleted; with `origin`, that only goes
+/// for the ones that process committed, since nobody else will broadcast
+/// the rest.
+pub async fn compact_events(
+    pool: &DbPool,
+    retain: i64,
+    origin: Option<&str>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM events
+        WHERE seq <= (SELECT MAX(seq) FROM events) - ?1
+          AND (delivered_at IS NOT NULL OR (?2 IS NOT NULL AND origin IS NOT ?2))
+        "#,
+    )
+    .bind(retain.max(1))
+    .bind(origin)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -1,5 +1,11 @@
+mod events;
 mod profiles;
+mod sessions;
 mod users;
+mod ws_tickets;
 
+pub use events::*;
 pub use profiles::*;
+pub use sessions::*;
 pub use users::*;
+pub use ws_tickets::*;
@@ -1,6 +1,6 @@
 use crate::DbPool;
 use shared::types::Profile;
-use sqlx::FromRow;
+use sqlx::{FromRow, SqliteExecutor};
 
 #[derive(Debug, FromRow)]
 struct ProfileRow {
@@ -8,6 +8,7 @@ struct ProfileRow {
     user_id: i64,
     display_name: String,
     bio: Option<String>,
+    version: i64,
     updated_at: String,
 }
 
@@ -18,61 +19,63 @@ impl From<ProfileRow> for Profile {
             user_id: row.user_id,
             display_name: row.display_name,
             bio: row.bio,
+            version: row.version,
             updated_at: row.updated_at,
         }
     }
 }
 
 pub async fn create_profile(
-    pool: &DbPool,
+    executor: impl SqliteExecutor<'_>,
     user_id: i64,
     display_name: &str,
 ) -> Result<Profile, sqlx::Error> {
-    sqlx::query(
+    let row: ProfileRow = sqlx::query_as(
         r#"
         INSERT INTO profiles (user_id, display_name)
         VALUES (?, ?)
+        RETURNING id, user_id, display_name, bio, version, updated_at
         "#,
     )
     .bind(user_id)
     .bind(display_name)
-    .execute(pool)
+    .fetch_one(executor)
     .await?;
 
-    // Fetch the created profile
-    get_profile_by_user_id(pool, user_id)
-        .await?
-        .ok_or(sqlx::Error::RowNotFound)
+    Ok(row.into())
 }
 
-pub async fn get_profile_by_id(pool: &DbPool, id: i64) -> Result<Option<Profile>, sqlx::Error> {
+pub async fn get_profile_by_id(
+    executor: impl SqliteExecutor<'_>,
+    id: i64,
+) -> Result<Option<Profile>, sqlx::Error> {
     let row: Option<ProfileRow> = sqlx::query_as(
         r#"
-        SELECT id, user_id, display_name, bio, updated_at
+        SELECT id, user_id, display_name, bio, version, updated_at
         FROM profiles
         WHERE id = ?
         "#,
     )
     .bind(id)
-    .fetch_optional(pool)
+    .fetch_optional(executor)
     .await?;
 
     Ok(row.map(Into::into))
 }
 
 pub async fn get_profile_by_user_id(
-    pool: &DbPool,
+    executor: impl SqliteExecutor<'_>,
     user_id: i64,
 ) -> Result<Option<Profile>, sqlx::Error> {
     let row: Option<ProfileRow> = sqlx::query_as(
         r#"
-        SELECT id, user_id, display_name, bio, updated_at
+        SELECT id, user_id, display_name, bio, version, updated_at
         FROM profiles
         WHERE user_id = ?
         "#,
     )
     .bind(user_id)
-    .fetch_optional(pool)
+    .fetch_optional(executor)
     .await?;
 
     Ok(row.map(Into::into))
@@ -81,7 +84,7 @@ pub async fn get_profile_by_user_id(
 pub async fn list_profiles(pool: &DbPool) -> Result<Vec<Profile>, sqlx::Error> {
     let rows: Vec<ProfileRow> = sqlx::query_as(
         r#"
-        SELECT id, user_id, display_name, bio, updated_at
+        SELECT id, user_id, display_name, bio, version, updated_at
         FROM profiles
         ORDER BY updated_at DESC
         "#,
@@ -93,18 +96,19 @@ pub async fn list_profiles(pool: &DbPool) -> Result<Vec<Profile>, sqlx::Error> {
 }
 
 pub async fn update_profile(
-    pool: &DbPool,
+    executor: impl SqliteExecutor<'_>,
     id: i64,
     display_name: Option<&str>,
     bio: Option<&str>,
 ) -> Result<Option<Profile>, sqlx::Error> {
     // Only update if we have something to update
     if display_name.is_none() && bio.is_none() {
-        return get_profile_by_id(pool, id).await;
+        return get_profile_by_id(executor, id).await;
     }
 
     // Build dynamic update query
-    let mut query = String::from("UPDATE profiles SET updated_at = datetime('now')");
+    let mut query =
+        String::from("UPDATE profiles SET updated_at = datetime('now'), version = version + 1");
 
     if display_name.is_some() {
         query.push_str(", display_name = ?");
@@ -112,9 +116,11 @@ pub async fn update_profile(
     if bio.is_some() {
         query.push_str(", bio = ?");
     }
-    query.push_str(" WHERE id = ?");
+    query.push_str(" WHERE id = ? RETURNING id, user_id, display_name, bio, version, updated_at");
 
-    let mut q = sqlx::query(&query);
+    // RETURNING hands back exactly the row this update wrote, even if
+    // another update lands right after it
+    let mut q = sqlx::query_as::<_, ProfileRow>(&query);
 
     if let Some(name) = display_name {
         q = q.bind(name);
@@ -124,7 +130,5 @@ pub async fn update_profile(
     }
     q = q.bind(id);
 
-    q.execute(pool).await?;
-
-    get_profile_by_id(pool, id).await
+    Ok(q.fetch_optional(executor).await?.map(Into::into))
 }
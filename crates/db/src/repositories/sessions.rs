@@ -0,0 +1,225 @@
+use crate::DbPool;
+use sqlx::{FromRow, SqliteExecutor};
+
+#[derive(Debug, Clone, FromRow)]
+pub struct SessionRow {
+    pub id: i64,
+    pub token: String,
+    pub user_id: i64,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: String,
+    pub last_seen_at: String,
+}
+
+pub async fn create_session(
+    pool: &DbPool,
+    token: &str,
+    user_id: i64,
+    user_agent: Option<&str>,
+    ip_address: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO sessions (token, user_id, user_agent, ip_address)
+        VALUES (?, ?, ?, ?)
+        "#,
+    )
+    .bind(token)
+    .bind(user_id)
+    .bind(user_agent)
+    .bind(ip_address)
+    .execute(pool)
+    .await?;
+
+    Ok(result.last_insert_rowid())
+}
+
+pub async fn get_session_by_token(
+    pool: &DbPool,
+    token: &str,
+) -> Result<Option<SessionRow>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT id, token, user_id, user_agent, ip_address, created_at, last_seen_at
+        FROM sessions
+        WHERE token = ?
+        "#,
+    )
+    .bind(token)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Whether a session exists and is within both lifetimes, without
+/// renewing it
+pub async fn session_is_live(
+    pool: &DbPool,
+    id: i64,
+    max_age_secs: u64,
+    idle_timeout_secs: u64,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM sessions
+            WHERE id = ?
+              AND created_at > datetime('now', ?)
+              AND last_seen_at > datetime('now', ?)
+        )
+        "#,
+    )
+    .bind(id)
+    .bind(seconds_ago(max_age_secs))
+    .bind(seconds_ago(idle_timeout_secs))
+    .fetch_one(pool)
+    .await
+}
+
+/// Delete a session by its cookie token, returning its id if it existed
+pub async fn delete_session_by_token(
+    executor: impl SqliteExecutor<'_>,
+    token: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        DELETE FROM sessions
+        WHERE token = ?
+        RETURNING id
+        "#,
+    )
+    .bind(token)
+    .fetch_optional(executor)
+    .await
+}
+
+/// Active sessions for a user, most recently used first
+pub async fn list_sessions_by_user(
+    pool: &DbPool,
+    user_id: i64,
+    max_age_secs: u64,
+    idle_timeout_secs: u64,
+) -> Result<Vec<SessionRow>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT id, token, user_id, user_agent, ip_address, created_at, last_seen_at
+        FROM sessions
+        WHERE user_id = ?
+          AND created_at > datetime('now', ?)
+          AND last_seen_at > datetime('now', ?)
+        ORDER BY last_seen_at DESC, id DESC
+        "#,
+    )
+    .bind(user_id)
+    .bind(seconds_ago(max_age_secs))
+    .bind(seconds_ago(idle_timeout_secs))
+    .fetch_all(pool)
+    .await
+}
+
+/// Delete one of a user's sessions. Returns false if it doesn't exist or
+/// belongs to someone else.
+pub async fn delete_user_session(
+    executor: impl SqliteExecutor<'_>,
+    user_id: i64,
+    id: i64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM sessions
+        WHERE id = ? AND user_id = ?
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Delete all of a user's sessions except `keep_id`, returning the deleted ids
+pub async fn delete_other_user_sessions(
+    executor: impl SqliteExecutor<'_>,
+    user_id: i64,
+    keep_id: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        DELETE FROM sessions
+        WHERE user_id = ? AND id != ?
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(keep_id)
+    .fetch_all(executor)
+    .await
+}
+
+/// Delete every session of a user, returning their ids
+pub async fn delete_user_sessions(
+    executor: impl SqliteExecutor<'_>,
+    user_id: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        DELETE FROM sessions
+        WHERE user_id = ?
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await
+}
+
+/// Bump `last_seen_at` if the session is still within both lifetimes.
+/// Returns false (and leaves the row untouched) if it has expired.
+pub async fn renew_session(
+    pool: &DbPool,
+    id: i64,
+    max_age_secs: u64,
+    idle_timeout_secs: u64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE sessions SET last_seen_at = datetime('now')
+        WHERE id = ?
+          AND created_at > datetime('now', ?)
+          AND last_seen_at > datetime('now', ?)
+        "#,
+    )
+    .bind(id)
+    .bind(seconds_ago(max_age_secs))
+    .bind(seconds_ago(idle_timeout_secs))
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Delete every session past either lifetime, returning their ids
+pub async fn delete_expired_sessions(
+    executor: impl SqliteExecutor<'_>,
+    max_age_secs: u64,
+    idle_timeout_secs: u64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        DELETE FROM sessions
+        WHERE created_at <= datetime('now', ?)
+           OR last_seen_at <= datetime('now', ?)
+        RETURNING id
+        "#,
+    )
+    .bind(seconds_ago(max_age_secs))
+    .bind(seconds_ago(idle_timeout_secs))
+    .fetch_all(executor)
+    .await
+}
+
+/// SQLite datetime modifier for "this many seconds before now"
+fn seconds_ago(secs: u64) -> String {
+    format!("-{secs} seconds")
+}
@@ -1,5 +1,5 @@
 use crate::DbPool;
-use sqlx::FromRow;
+use sqlx::{FromRow, SqliteExecutor};
 
 #[derive(Debug, FromRow)]
 pub struct UserRow {
@@ -53,3 +53,18 @@ pub async fn get_user_by_id(pool: &DbPool, id: i64) -> Result<Option<UserRow>, s
     .fetch_optional(pool)
     .await
 }
+
+/// Delete a user. Their profile and sessions go with them.
+pub async fn delete_user(executor: impl SqliteExecutor<'_>, id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM users
+        WHERE id = ?
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
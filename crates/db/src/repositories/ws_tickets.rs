@@ -0,0 +1,66 @@
+use crate::DbPool;
+
+pub async fn create_ws_ticket(
+    pool: &DbPool,
+    ticket: &str,
+    session_id: i64,
+    ttl_secs: u64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO ws_tickets (ticket, session_id, expires_at)
+        VALUES (?, ?, datetime('now', ?))
+        "#,
+    )
+    .bind(ticket)
+    .bind(session_id)
+    .bind(format!("+{ttl_secs} seconds"))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// The token of the session a ticket was issued for, if the ticket exists
+/// and hasn't expired. Leaves the ticket in place.
+pub async fn find_ws_ticket(pool: &DbPool, ticket: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT sessions.token FROM ws_tickets
+        JOIN sessions ON sessions.id = ws_tickets.session_id
+        WHERE ws_tickets.ticket = ? AND ws_tickets.expires_at > datetime('now')
+        "#,
+    )
+    .bind(ticket)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Delete a ticket if it exists and hasn't expired. Tickets are single-use,
+/// so only one caller gets `true` for each.
+pub async fn consume_ws_ticket(pool: &DbPool, ticket: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM ws_tickets
+        WHERE ticket = ? AND expires_at > datetime('now')
+        "#,
+    )
+    .bind(ticket)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn delete_expired_ws_tickets(pool: &DbPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM ws_tickets
+        WHERE expires_at <= datetime('now')
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
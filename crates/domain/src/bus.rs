@@ -0,0 +1,469 @@
+use crate::events::{decode, local_head, local_replay_since, publishable, replay_covers};
+use async_trait::async_trait;
+use db::{DbPool, EventRow};
+use shared::types::SequencedEvent;
+use sqlx::postgres::{PgListener, PgPool};
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify, OnceCell};
+
+/// Postgres channel the `postgres` bus notifies on
+const NOTIFY_CHANNEL: &str = "rustcard_events";
+
+/// Advisory lock held while appending to the `postgres` bus's log, so
+/// events commit in sequence order
+const LOG_LOCK: i64 = 0x7275_7374_6361_7264;
+
+/// How long to wait before retrying after the bus loses its database
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An event as the bus delivers it. Merged and skipped events leave gaps
+/// in the sequence, so each event says where its range starts: a
+/// subscriber that has everything up to `after` is missing nothing before
+/// this event, and one that doesn't has to read the rest from the log.
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub after: i64,
+    pub event: SequencedEvent,
+}
+
+/// EventBus carries events from the outbox to live subscribers. The relay
+/// hands every committed event to `publish`, and `subscribe` yields the
+/// events this process's connections should see. Buses shared between
+/// processes deliver every process's events to every other.
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    /// Send events the relay took from the outbox, oldest first
+    async fn publish(&self, events: Vec<Published>) -> Result<(), sqlx::Error>;
+
+    fn subscribe(&self) -> broadcast::Receiver<Published>;
+
+    /// Whether the bus reaches every process, so any relay may publish any
+    /// process's events. Other buses leave each process's events to its own
+    /// relay.
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    /// Receive events from other processes until the process exits. Buses
+    /// that only see their own process have nothing to do.
+    async fn listen(&self) {
+        std::future::pending().await
+    }
+
+    /// Sequence number of the newest event, 0 if there are none. Buses
+    /// that number events as this process's `events` table does read it
+    /// from there; `local` is that table's database.
+    async fn head(&self, local: &DbPool) -> Result<i64, sqlx::Error> {
+        local_head(local).await
+    }
+
+    /// Every event after `since`, oldest first, or `None` if some of them
+    /// have been compacted away or `since` is ahead of the log
+    async fn replay_since(
+        &self,
+        local: &DbPool,
+        since: i64,
+    ) -> Result<Option<Vec<SequencedEvent>>, sqlx::Error> {
+        local_replay_since(local, since).await
+    }
+
+    /// Drop all but the newest `retain` events from a log the bus keeps
+    /// itself. Returns how many were dropped.
+    async fn compact(&self, _retain: i64) -> Result<u64, sqlx::Error> {
+        Ok(0)
+    }
+}
+
+/// Delivers events to connections in this process only
+pub struct InProcessBus {
+    tx: broadcast::Sender<Published>,
+}
+
+impl InProcessBus {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+}
+
+#[async_trait]
+impl EventBus for InProcessBus {
+    async fn publish(&self, events: Vec<Published>) -> Result<(), sqlx::Error> {
+        for event in events {
+            // No receivers just means nobody is connected right now
+            let _ = self.tx.send(event);
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Published> {
+        self.tx.subscribe()
+    }
+}
+
+/// A log a [`LogTail`] follows
+#[async_trait]
+trait TailSource: Send + Sync {
+    /// Sequence number of the newest event, 0 if there are none
+    async fn head(&self) -> Result<i64, sqlx::Error>;
+
+    /// Every event after `since`, oldest first
+    async fn rows_since(&self, since: i64) -> Result<Vec<EventRow>, sqlx::Error>;
+}
+
+/// The `events` table every process writes to
+#[async_trait]
+impl TailSource for DbPool {
+    async fn head(&self) -> Result<i64, sqlx::Error> {
+        local_head(self).await
+    }
+
+    async fn rows_since(&self, since: i64) -> Result<Vec<EventRow>, sqlx::Error> {
+        db::list_events_since(self, since).await
+    }
+}
+
+/// Follows a log shared by every process and broadcasts what it finds
+/// locally; the shared buses differ in which log and in what wakes it.
+/// Both logs commit one append at a time, so events become visible in
+/// sequence order and reading past the last one seen never skips any.
+struct LogTail<S> {
+    source: S,
+    tx: broadcast::Sender<Published>,
+    coalesce: bool,
+}
+
+impl<S: TailSource> LogTail<S> {
+    fn new(source: S, capacity: usize, coalesce: bool) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            source,
+            tx,
+            coalesce,
+        }
+    }
+
+    /// Where to start following from: the head of the log. Events before it
+    /// are caught up on by the connections themselves.
+    async fn start(&self) -> i64 {
+        loop {
+            match self.source.head().await {
+                Ok(head) => return head,
+                Err(e) => {
+                    tracing::error!("Failed to read the event log head: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Broadcast every event after `cursor` and move it past them
+    async fn catch_up(&self, cursor: &mut i64) {
+        let rows = match self.source.rows_since(*cursor).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                tracing::error!("Failed to read the event log: {}", e);
+                return;
+            }
+        };
+        let Some(last) = rows.last().map(|row| row.seq) else {
+            return;
+        };
+        for event in publishable(*cursor, rows, self.coalesce) {
+            let _ = self.tx.send(event);
+        }
+        *cursor = last;
+    }
+}
+
+/// Shares events between processes by having each of them poll the
+/// `events` table. Needs nothing but the database, at the cost of up to
+/// one `interval` of latency for events from other processes.
+pub struct PollingBus {
+    tail: LogTail<DbPool>,
+    interval: Duration,
+    /// Wakes the poller when this process publishes, so local events don't
+    /// wait for the next poll
+    published: Notify,
+}
+
+impl PollingBus {
+    pub fn new(db: DbPool, capacity: usize, interval: Duration, coalesce: bool) -> Self {
+        Self {
+            tail: LogTail::new(db, capacity, coalesce),
+            interval,
+            published: Notify::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventBus for PollingBus {
+    async fn publish(&self, _events: Vec<Published>) -> Result<(), sqlx::Error> {
+        // They're already in the table
+        self.published.notify_one();
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Published> {
+        self.tail.tx.subscribe()
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    async fn listen(&self) {
+        let mut cursor = self.tail.start().await;
+        loop {
+            tokio::select! {
+                _ = self.published.notified() => {}
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+            self.tail.catch_up(&mut cursor).await;
+        }
+    }
+}
+
+/// The `postgres` bus's log: the `rustcard_events` table, numbered anew.
+/// `local_seq` is where each event is in the `events` table, so an event
+/// relayed twice (by two instances, or again after a crash) is appended
+/// once. The payload is compared too, in case the database was replaced
+/// and is numbering its events from the start again.
+struct PgLog {
+    pg: PgPool,
+    /// Set once the table exists
+    ready: OnceCell<()>,
+}
+
+impl PgLog {
+    /// Create the table on first use. The lock stops instances that start
+    /// together from racing to create it.
+    async fn ensure(&self) -> Result<(), sqlx::Error> {
+        self.ready
+            .get_or_try_init(|| async {
+                let mut tx = self.pg.begin().await?;
+                sqlx::query("SELECT pg_advisory_xact_lock($1)")
+                    .bind(LOG_LOCK)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(
+                    r#"
+                    CREATE TABLE IF NOT EXISTS rustcard_events (
+                        seq BIGSERIAL PRIMARY KEY,
+                        local_seq BIGINT NOT NULL,
+                        payload TEXT NOT NULL,
+                        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                    )
+                    "#,
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query(
+                    r#"
+                    CREATE INDEX IF NOT EXISTS idx_rustcard_events_local_seq
+                    ON rustcard_events(local_seq)
+                    "#,
+                )
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await
+            })
+            .await
+            .map(drop)
+    }
+
+    /// Oldest and newest sequence numbers in the log, if any
+    async fn bounds(&self) -> Result<Option<(i64, i64)>, sqlx::Error> {
+        self.ensure().await?;
+        let (min, max): (Option<i64>, Option<i64>) =
+            sqlx::query_as("SELECT MIN(seq), MAX(seq) FROM rustcard_events")
+                .fetch_one(&self.pg)
+                .await?;
+        Ok(min.zip(max))
+    }
+
+    /// Append events that aren't in the log yet, oldest first, and wake
+    /// every listener. The lock makes appends commit in sequence order.
+    async fn append(
+        &self,
+        events: impl Iterator<Item = SequencedEvent> + Send,
+    ) -> Result<(), sqlx::Error> {
+        self.ensure().await?;
+
+        let mut tx = self.pg.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(LOG_LOCK)
+            .execute(&mut *tx)
+            .await?;
+        for event in events {
+            let payload = serde_json::to_string(&event.event)
+                .map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+            // Checked under the lock rather than by a constraint, so a
+            // duplicate doesn't use up a sequence number and leave a gap
+            sqlx::query(
+                r#"
+                INSERT INTO rustcard_events (local_seq, payload)
+                SELECT $1, $2
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM rustcard_events WHERE local_seq = $1 AND payload = $2
+                )
+                "#,
+            )
+            .bind(event.seq)
+            .bind(payload)
+            .execute(&mut *tx)
+            .await?;
+        }
+        // Delivered when the transaction commits
+        sqlx::query("SELECT pg_notify($1, '')")
+            .bind(NOTIFY_CHANNEL)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    async fn compact(&self, retain: i64) -> Result<u64, sqlx::Error> {
+        self.ensure().await?;
+        let result = sqlx::query(
+            r#"
+            DELETE FROM rustcard_events
+            WHERE seq <= (SELECT MAX(seq) FROM rustcard_events) - $1
+            "#,
+        )
+        .bind(retain.max(1))
+        .execute(&self.pg)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl TailSource for PgLog {
+    async fn head(&self) -> Result<i64, sqlx::Error> {
+        Ok(self.bounds().await?.map_or(0, |(_, max)| max))
+    }
+
+    async fn rows_since(&self, since: i64) -> Result<Vec<EventRow>, sqlx::Error> {
+        self.ensure().await?;
+        sqlx::query_as(
+            r#"
+            SELECT seq, payload, created_at::text AS created_at
+            FROM rustcard_events
+            WHERE seq > $1
+            ORDER BY seq
+            "#,
+        )
+        .bind(since)
+        .fetch_all(&self.pg)
+        .await
+    }
+}
+
+/// Shares events between processes through a log in Postgres, for
+/// instances that share a database but shouldn't all poll it. Each relay
+/// appends its events to the log and `NOTIFY`s the others, who read what's
+/// new. Sequence numbers on this bus are the log's, so catching up reads
+/// from it too.
+pub struct PostgresBus {
+    tail: LogTail<PgLog>,
+}
+
+impl PostgresBus {
+    /// Set up the bus without connecting yet; a bad URL is the only error
+    pub fn new(postgres_url: &str, capacity: usize, coalesce: bool) -> Result<Self, sqlx::Error> {
+        let log = PgLog {
+            pg: PgPool::connect_lazy(postgres_url)?,
+            ready: OnceCell::new(),
+        };
+        Ok(Self {
+            tail: LogTail::new(log, capacity, coalesce),
+        })
+    }
+
+    fn log(&self) -> &PgLog {
+        &self.tail.source
+    }
+
+    async fn connect_listener(&self) -> PgListener {
+        loop {
+            let listener = async {
+                let mut listener = PgListener::connect_with(&self.log().pg).await?;
+                listener.listen(NOTIFY_CHANNEL).await?;
+                Ok::<_, sqlx::Error>(listener)
+            };
+            match listener.await {
+                Ok(listener) => return listener,
+                Err(e) => {
+                    tracing::error!("Failed to listen for events on Postgres: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventBus for PostgresBus {
+    async fn publish(&self, events: Vec<Published>) -> Result<(), sqlx::Error> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        // The log numbers them anew, without gaps
+        let events = events.into_iter().map(|published| published.event);
+        self.log().append(events).await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Published> {
+        self.tail.tx.subscribe()
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    async fn listen(&self) {
+        let mut cursor = self.tail.start().await;
+        let mut listener = self.connect_listener().await;
+        // Anything committed before we started listening
+        self.tail.catch_up(&mut cursor).await;
+
+        loop {
+            // The listener reconnects by itself; notifications sent while
+            // it was away are covered by the next catch-up
+            match listener.recv().await {
+                Ok(_) => self.tail.catch_up(&mut cursor).await,
+                Err(e) => {
+                    tracing::error!("Lost the Postgres event listener: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    self.tail.catch_up(&mut cursor).await;
+                }
+            }
+        }
+    }
+
+    async fn head(&self, _local: &DbPool) -> Result<i64, sqlx::Error> {
+        self.log().head().await
+    }
+
+    async fn replay_since(
+        &self,
+        _local: &DbPool,
+        since: i64,
+    ) -> Result<Option<Vec<SequencedEvent>>, sqlx::Error> {
+        if !replay_covers(self.log().bounds().await?, since) {
+            return Ok(None);
+        }
+        self.log()
+            .rows_since(since)
+            .await?
+            .into_iter()
+            .map(decode)
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    async fn compact(&self, retain: i64) -> Result<u64, sqlx::Error> {
+        self.log().compact(retain).await
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{EventBus, Published};
+use db::{DbPool, EventRow};
+use shared::types::{SequencedEvent, WsEvent};
+use sqlx::{Sqlite, SqliteConnection, Transaction};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, Notify};
+use uuid::Uuid;
+
+/// EventLog is the durable, sequenced stream behind realtime updates.
+/// Events are written to the `events` table in the same transaction as the
+/// change they describe (see [`Outbox`]). A relay then publishes them on
+/// the [`EventBus`] to live subscribers, tagged with their sequence number,
+/// and marks them delivered, so an event can't be lost between the write
+/// and the broadcast. On a shared bus, any process's relay publishes
+/// whatever is undelivered, including what a crashed process left behind.
+/// Otherwise each relay publishes only what its own process committed:
+/// the bus only reaches that process's connections, and a crashed
+/// process's connections went with it. Clients that drop off catch up from
+/// the table.
+///
+/// Profile patches can optionally be coalesced: when patches are waiting,
+/// the relay waits out a window before broadcasting, and the patches to
+/// each profile within it go out merged into one.
+#[derive(Clone)]
+pub struct EventLog {
+    db: DbPool,
+    bus: Arc<dyn EventBus>,
+    coalesce_window: Option<Duration>,
+    /// Wakes the relay when an outbox commits
+    committed: Arc<Notify>,
+    /// Identifies this process's events in the table
+    origin: Arc<str>,
+}
+
+/// A transaction that records events alongside the changes it makes. Its
+/// events reach subscribers only if it commits.
+pub struct Outbox {
+    tx: Transaction<'static, Sqlite>,
+    committed: Arc<Notify>,
+    origin: Arc<str>,
+}
+
+impl Outbox {
+    /// The transaction's connection, for making the change itself
+    pub fn conn(&mut self) -> &mut SqliteConnection {
+        &mut self.tx
+    }
+
+    pub async fn record(&mut self, event: &WsEvent) -> Result<(), sqlx::Error> {
+        let payload = serde_json::to_string(event).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+        db::append_event(&mut *self.tx, &payload, Some(&self.origin))
+            .await
+            .map(drop)
+    }
+
+    /// Commit the change and its events, and wake the relay
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        self.tx.commit().await?;
+        self.committed.notify_one();
+        Ok(())
+    }
+}
+
+impl EventLog {
+    pub fn new(db: DbPool, bus: Arc<dyn EventBus>) -> Self {
+        Self {
+            db,
+            bus,
+            coalesce_window: None,
+            committed: Arc::default(),
+            origin: Uuid::new_v4().to_string().into(),
+        }
+    }
+
+    /// Coalesce profile patches: once woken with patches waiting, the relay
+    /// waits this long before broadcasting, and merges the patches to each
+    /// profile. A zero window broadcasts every event right away.
+    pub fn with_coalesce_window(mut self, window: Duration) -> Self {
+        self.coalesce_window = (!window.is_zero()).then_some(window);
+        self
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Published> {
+        self.bus.subscribe()
+    }
+
+    /// Start a transaction for a change that publishes events
+    pub async fn begin(&self) -> Result<Outbox, sqlx::Error> {
+        Ok(Outbox {
+            tx: self.db.begin().await?,
+            committed: self.committed.clone(),
+            origin: self.origin.clone(),
+        })
+    }
+
+    /// Record an event that doesn't go with any other change
+    pub async fn publish(&self, event: WsEvent) -> Result<(), sqlx::Error> {
+        let mut outbox = self.begin().await?;
+        outbox.record(&event).await?;
+        outbox.commit().await
+    }
+
+    /// Relay events to the bus as outboxes commit. Also checks every
+    /// `poll_interval` for events nothing woke it for, such as those left
+    /// over from before a restart. Runs forever, along with the bus's own
+    /// listener.
+    pub async fn run_relay(self, poll_interval: Duration) {
+        tokio::join!(self.relay(poll_interval), self.bus.listen());
+    }
+
+    async fn relay(&self, poll_interval: Duration) {
+        loop {
+            if let Err(e) = self.relay_pending().await {
+                tracing::error!("Failed to relay events: {}", e);
+            }
+
+            tokio::select! {
+                _ = self.committed.notified() => {}
+                _ = tokio::time::sleep(poll_interval) => {}
+            }
+            if let Some(window) = self.coalesce_window {
+                // Only patches are merged, so nothing else is held back. A
+                // failed read shows up again when relaying.
+                if self.patches_pending().await.unwrap_or(false) {
+                    tokio::time::sleep(window).await;
+                }
+            }
+        }
+    }
+
+    /// Publish every undelivered event this relay is responsible for, then
+    /// mark them delivered. If the process dies in between they may be
+    /// published again, which subscribers shrug off since they skip
+    /// sequence numbers they've seen.
+    pub async fn relay_pending(&self) -> Result<usize, sqlx::Error> {
+        let rows = db::list_undelivered_events(&self.db, self.relayed_origin()).await?;
+        let (Some(first), Some(last)) = (rows.first(), rows.last()) else {
+            return Ok(0);
+        };
+        // Whatever came before the first is someone else's to account for
+        let (after, last) = (first.seq - 1, last.seq);
+
+        let events = publishable(after, rows, self.coalesce_window.is_some());
+        let count = events.len();
+        self.bus.publish(events).await?;
+        db::mark_events_delivered(&self.db, last, self.relayed_origin()).await?;
+        Ok(count)
+    }
+
+    /// Whether any event waiting to be relayed is a profile patch
+    async fn patches_pending(&self) -> Result<bool, sqlx::Error> {
+        let rows = db::list_undelivered_events(&self.db, self.relayed_origin()).await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| decode(row).ok())
+            .any(|event| matches!(event.event, WsEvent::ProfilePatch(_))))
+    }
+
+    /// Whose events this relay publishes: anyone's on a shared bus,
+    /// otherwise only this process's
+    fn relayed_origin(&self) -> Option<&str> {
+        (!self.bus.is_shared()).then_some(&self.origin)
+    }
+
+    /// Sequence number of the newest event (0 if nothing was ever published)
+    pub async fn head(&self) -> Result<i64, sqlx::Error> {
+        self.bus.head(&self.db).await
+    }
+
+    /// Every event after `since`, oldest first. Returns `None` if the log
+    /// can't tell the whole story: some of those events have been compacted
+    /// away, or `since` is ahead of the log.
+    pub async fn replay_since(
+        &self,
+        since: i64,
+    ) -> Result<Option<Vec<SequencedEvent>>, sqlx::Error> {
+        let Some(events) = self.bus.replay_since(&self.db, since).await? else {
+            return Ok(None);
+        };
+        // Replay patches merged the way the relay publishes them, so a
+        // subscriber filling a gap the merging left gets the same patch
+        Ok(Some(match self.coalesce_window {
+            Some(_) => coalesce_patches(events),
+            None => events,
+        }))
+    }
+
+    /// Drop all but the newest `retain` events, from the bus's log too if
+    /// it keeps one
+    pub async fn compact(&self, retain: i64) -> Result<u64, sqlx::Error> {
+        let local = db::compact_events(&self.db, retain, self.relayed_origin()).await?;
+        Ok(local + self.bus.compact(retain).await?)
+    }
+}
+
+/// Head of this process's `events` table
+pub(crate) async fn local_head(db: &DbPool) -> Result<i64, sqlx::Error> {
+    Ok(db::get_event_bounds(db).await?.map_or(0, |(_, max)| max))
+}
+
+/// Replay this process's `events` table after `since`
+pub(crate) async fn local_replay_since(
+    db: &DbPool,
+    since: i64,
+) -> Result<Option<Vec<SequencedEvent>>, sqlx::Error> {
+    if !replay_covers(db::get_event_bounds(db).await?, since) {
+        return Ok(None);
+    }
+    db::list_events_since(db, since)
+        .await?
+        .into_iter()
+        .map(decode)
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Whether a log holding `bounds` has every event after `since`
+pub(crate) fn replay_covers(bounds: Option<(i64, i64)>, since: i64) -> bool {
+    let (min, max) = bounds.unwrap_or((1, 0));
+    since <= max && since >= min - 1
+}
+
+pub(crate) fn decode(row: EventRow) -> Result<SequencedEvent, sqlx::Error> {
+    let event = serde_json::from_str(&row.payload).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+    Ok(SequencedEvent {
+        seq: row.seq,
+        event,
+    })
+}
+
+/// What the bus should publish for `rows`, read from a log after `after`.
+/// A row that can't be decoded is skipped rather than blocking everything
+/// behind it, and with `coalesce` patches are merged. Each event accounts
+/// for the rows since the one before it, back to `after`, unless a
+/// sequence number in between wasn't among the rows.
+pub(crate) fn publishable(after: i64, rows: Vec<EventRow>, coalesce: bool) -> Vec<Published> {
+    let seqs: Vec<i64> = rows.iter().map(|row| row.seq).collect();
+    let mut events: Vec<SequencedEvent> = rows
+        .into_iter()
+        .filter_map(|row| {
+            let seq = row.seq;
+            decode(row)
+                .inspect_err(|e| tracing::error!("Skipping undecodable event {}: {}", seq, e))
+                .ok()
+        })
+        .collect();
+    if coalesce {
+        events = coalesce_patches(events);
+    }
+
+    let mut seqs = seqs.into_iter().peekable();
+    // Every sequence number up to `read` is among the rows or before them
+    let (mut after, mut read) = (after, after);
+    events
+        .into_iter()
+        .map(|event| {
+            while let Some(seq) = seqs.next_if(|&seq| seq <= event.seq) {
+                if seq != read + 1 {
+                    after = seq - 1;
+                }
+                read = seq;
+            }
+            let published = Published { after, event };
+            after = published.event.seq;
+            published
+        })
+        .collect()
+}
+
+/// Merge each run of patches to the same profile into one, sent where the
+/// last of them was. Any other event about the profile ends the run, so a
+/// merged patch never jumps ahead of it.
+pub(crate) fn coalesce_patches(events: Vec<SequencedEvent>) -> Vec<SequencedEvent> {
+    let mut merged: Vec<Option<SequencedEvent>> = Vec::with_capacity(events.len());
+    // Index in `merged` of the latest patch to each profile
+    let mut runs: HashMap<i64, usize> = HashMap::new();
+
+    for mut event in events {
+        match &mut event.event {
+            WsEvent::ProfilePatch(patch) => {
+                let earlier = runs
+                    .insert(patch.id, merged.len())
+                    .and_then(|i| merged[i].take());
+                if let Some(SequencedEvent {
+                    event: WsEvent::ProfilePatch(mut earlier),
+                    ..
+                }) = earlier
+                {
+                    // Send the earlier patch here, with this one folded in
+                    std::mem::swap(patch, &mut earlier);
+                    patch.merge(earlier);
+                }
+            }
+            WsEvent::Profile(profile) => {
+                runs.remove(&profile.id);
+            }
+            WsEvent::Deleted { id, .. } => {
+                runs.remove(id);
+            }
+            _ => {}
+        }
+        merged.push(Some(event));
+    }
+
+    merged.into_iter().flatten().collect()
+}
@@ -1,3 +1,11 @@
+mod bus;
+mod events;
+mod presence;
 mod profiles;
+mod sessions;
 
-pub use profiles::ProfileService;
+pub use bus::{EventBus, InProcessBus, PollingBus, PostgresBus, Published};
+pub use events::{EventLog, Outbox};
+pub use presence::PresenceRegistry;
+pub use profiles::{ProfileError, ProfileService};
+pub use sessions::{SessionLifetimes, SessionService, SessionStatus};
@@ -0,0 +1,65 @@
+use shared::types::WsEvent;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// PresenceRegistry tracks which users have an open WebSocket right now.
+/// A user with several tabs open counts once: `PresenceJoined` is broadcast
+/// when their first connection opens and `PresenceLeft` when the last one
+/// closes. Presence is per process and isn't written to the event log.
+#[derive(Clone)]
+pub struct PresenceRegistry {
+    connections: Arc<Mutex<HashMap<i64, usize>>>,
+    tx: broadcast::Sender<WsEvent>,
+}
+
+impl PresenceRegistry {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            connections: Arc::default(),
+            tx,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WsEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Record a new connection for a user
+    pub fn join(&self, user_id: i64) {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(user_id).or_default();
+        *count += 1;
+        if *count == 1 {
+            let _ = self.tx.send(WsEvent::PresenceJoined { user_id });
+        }
+    }
+
+    /// Record that one of a user's connections closed
+    pub fn leave(&self, user_id: i64) {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(count) = connections.get_mut(&user_id) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            connections.remove(&user_id);
+            let _ = self.tx.send(WsEvent::PresenceLeft { user_id });
+        }
+    }
+
+    /// Ids of every user who is online, in ascending order
+    pub fn online(&self) -> Vec<i64> {
+        let connections = self.connections.lock().unwrap();
+        let mut ids: Vec<i64> = connections.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn is_online(&self, user_id: i64) -> bool {
+        self.connections.lock().unwrap().contains_key(&user_id)
+    }
+}
@@ -1,18 +1,32 @@
+use crate::{sessions::record_revocations, EventLog};
 use db::DbPool;
-use shared::types::{Profile, WsEvent};
-use tokio::sync::broadcast;
+use shared::types::{Profile, ProfileFields, ProfilePatch, WsEvent};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("Profile not found")]
+    NotFound,
+
+    #[error("Profile belongs to another user")]
+    NotOwner,
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
 
 /// ProfileService centralizes all profile mutations.
-/// Broadcasts happen automatically after successful DB writes.
+/// Each mutation records its events in the same transaction, so they are
+/// published if and only if the change is committed.
 #[derive(Clone)]
 pub struct ProfileService {
     db: DbPool,
-    events_tx: broadcast::Sender<WsEvent>,
+    events: EventLog,
 }
 
 impl ProfileService {
-    pub fn new(db: DbPool, events_tx: broadcast::Sender<WsEvent>) -> Self {
-        Self { db, events_tx }
+    pub fn new(db: DbPool, events: EventLog) -> Self {
+        Self { db, events }
     }
 
     /// Create a new profile for a user (called during registration)
@@ -21,25 +35,87 @@ impl ProfileService {
         user_id: i64,
         display_name: &str,
     ) -> Result<Profile, sqlx::Error> {
-        let profile = db::create_profile(&self.db, user_id, display_name).await?;
-        let _ = self.events_tx.send(WsEvent::Profile(profile.clone()));
+        let mut outbox = self.events.begin().await?;
+        let profile = db::create_profile(outbox.conn(), user_id, display_name).await?;
+        outbox.record(&WsEvent::Profile(profile.clone())).await?;
+        outbox.commit().await?;
         Ok(profile)
     }
 
-    /// Update an existing profile
+    /// Update an existing profile. Subscribers get a patch with just the
+    /// fields passed here; an update that sets nothing publishes nothing.
     pub async fn update_profile(
         &self,
         id: i64,
         display_name: Option<&str>,
         bio: Option<&str>,
     ) -> Result<Option<Profile>, sqlx::Error> {
-        let profile = db::update_profile(&self.db, id, display_name, bio).await?;
+        let mut outbox = self.events.begin().await?;
+        let profile = db::update_profile(outbox.conn(), id, display_name, bio).await?;
         if let Some(ref p) = profile {
-            let _ = self.events_tx.send(WsEvent::Profile(p.clone()));
+            if display_name.is_some() || bio.is_some() {
+                outbox
+                    .record(&WsEvent::ProfilePatch(ProfilePatch {
+                        id: p.id,
+                        user_id: p.user_id,
+                        base_version: p.version - 1,
+                        version: p.version,
+                        changed_fields: ProfileFields {
+                            display_name: display_name.map(str::to_string),
+                            bio: bio.map(str::to_string),
+                        },
+                        updated_at: p.updated_at.clone(),
+                    }))
+                    .await?;
+            }
         }
+        outbox.commit().await?;
         Ok(profile)
     }
 
+    /// Update a profile on behalf of a user, who must own it
+    pub async fn update_own_profile(
+        &self,
+        user_id: i64,
+        id: i64,
+        display_name: Option<&str>,
+        bio: Option<&str>,
+    ) -> Result<Profile, ProfileError> {
+        let profile = self
+            .get_profile_by_id(id)
+            .await?
+            .ok_or(ProfileError::NotFound)?;
+
+        if profile.user_id != user_id {
+            return Err(ProfileError::NotOwner);
+        }
+
+        self.update_profile(id, display_name, bio)
+            .await?
+            .ok_or(ProfileError::NotFound)
+    }
+
+    /// Remove a user's account along with their profile, revoking every
+    /// session, all in one transaction. Returns false if the user didn't
+    /// exist.
+    pub async fn delete_account(&self, user_id: i64) -> Result<bool, sqlx::Error> {
+        let mut outbox = self.events.begin().await?;
+        let sessions = db::delete_user_sessions(outbox.conn(), user_id).await?;
+        record_revocations(&mut outbox, sessions).await?;
+
+        let profile = db::get_profile_by_user_id(outbox.conn(), user_id).await?;
+        if !db::delete_user(outbox.conn(), user_id).await? {
+            return Ok(false);
+        }
+
+        if let Some(ref p) = profile {
+            outbox.record(&deleted(p)).await?;
+        }
+        outbox.record(&WsEvent::Removed { user_id }).await?;
+        outbox.commit().await?;
+        Ok(true)
+    }
+
     /// Get a single profile by ID (no broadcast)
     pub async fn get_profile_by_id(&self, id: i64) -> Result<Option<Profile>, sqlx::Error> {
         db::get_profile_by_id(&self.db, id).await
@@ -58,3 +134,10 @@ impl ProfileService {
         db::list_profiles(&self.db).await
     }
 }
+
+fn deleted(profile: &Profile) -> WsEvent {
+    WsEvent::Deleted {
+        id: profile.id,
+        user_id: profile.user_id,
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{EventLog, Outbox};
+use db::{DbPool, SessionRow};
+use shared::types::WsEvent;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long a session may live in total, and without being used.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionLifetimes {
+    pub absolute: Duration,
+    pub idle: Duration,
+}
+
+/// Outcome of looking up a session token.
+#[derive(Debug)]
+pub enum SessionStatus {
+    Active(SessionRow),
+    Expired,
+    Missing,
+}
+
+/// SessionService owns the login sessions behind the `session_id` cookie.
+/// Sessions live in the database so they survive restarts and are shared
+/// between API processes. Ending a session records a `SessionRevoked` event
+/// in the same transaction, so long-lived connections opened with it are
+/// closed on every instance the event bus reaches.
+#[derive(Clone)]
+pub struct SessionService {
+    db: DbPool,
+    events: EventLog,
+    lifetimes: SessionLifetimes,
+}
+
+impl SessionService {
+    pub fn new(db: DbPool, events: EventLog, lifetimes: SessionLifetimes) -> Self {
+        Self {
+            db,
+            events,
+            lifetimes,
+        }
+    }
+
+    pub fn lifetimes(&self) -> SessionLifetimes {
+        self.lifetimes
+    }
+
+    /// Start a new session for a user and return its cookie token
+    pub async fn create_session(
+        &self,
+        user_id: i64,
+        user_agent: Option<&str>,
+        ip_address: Option<&str>,
+    ) -> Result<String, sqlx::Error> {
+        let token = Uuid::new_v4().to_string();
+        db::create_session(&self.db, &token, user_id, user_agent, ip_address).await?;
+        Ok(token)
+    }
+
+    /// Look up a session by cookie token. Active sessions have their idle
+    /// window renewed; expired ones are deleted on the spot.
+    pub async fn resolve_session(&self, token: &str) -> Result<SessionStatus, sqlx::Error> {
+        let Some(session) = db::get_session_by_token(&self.db, token).await? else {
+            return Ok(SessionStatus::Missing);
+        };
+
+        let renewed = db::renew_session(
+            &self.db,
+            session.id,
+            self.lifetimes.absolute.as_secs(),
+            self.lifetimes.idle.as_secs(),
+        )
+        .await?;
+
+        if renewed {
+            Ok(SessionStatus::Active(session))
+        } else {
+            self.delete_session(token).await?;
+            Ok(SessionStatus::Expired)
+        }
+    }
+
+    /// Whether a session still exists and hasn't expired. Unlike
+    /// `resolve_session`, this doesn't count as using it.
+    pub async fn session_is_live(&self, id: i64) -> Result<bool, sqlx::Error> {
+        db::session_is_live(
+            &self.db,
+            id,
+            self.lifetimes.absolute.as_secs(),
+            self.lifetimes.idle.as_secs(),
+        )
+        .await
+    }
+
+    /// End a session (logout)
+    pub async fn delete_session(&self, token: &str) -> Result<(), sqlx::Error> {
+        let mut outbox = self.events.begin().await?;
+        let id = db::delete_session_by_token(outbox.conn(), token).await?;
+        record_revocations(&mut outbox, id).await?;
+        outbox.commit().await
+    }
+
+    /// A user's active sessions, most recently used first
+    pub async fn list_sessions(&self, user_id: i64) -> Result<Vec<SessionRow>, sqlx::Error> {
+        db::list_sessions_by_user(
+            &self.db,
+            user_id,
+            self.lifetimes.absolute.as_secs(),
+            self.lifetimes.idle.as_secs(),
+        )
+        .await
+    }
+
+    /// Revoke one of a user's sessions. Returns false if the user has no
+    /// such session.
+    pub async fn revoke_session(&self, user_id: i64, id: i64) -> Result<bool, sqlx::Error> {
+        let mut outbox = self.events.begin().await?;
+        let revoked = db::delete_user_session(outbox.conn(), user_id, id).await?;
+        record_revocations(&mut outbox, revoked.then_some(id)).await?;
+        outbox.commit().await?;
+        Ok(revoked)
+    }
+
+    /// Revoke every session of a user except `keep_id`, returning how many
+    /// were revoked
+    pub async fn revoke_other_sessions(
+        &self,
+        user_id: i64,
+        keep_id: i64,
+    ) -> Result<usize, sqlx::Error> {
+        let mut outbox = self.events.begin().await?;
+        let ids = db::delete_other_user_sessions(outbox.conn(), user_id, keep_id).await?;
+        let count = ids.len();
+        record_revocations(&mut outbox, ids).await?;
+        outbox.commit().await?;
+        Ok(count)
+    }
+
+    /// Issue a single-use ticket that stands in for a session's cookie when
+    /// opening a WebSocket
+    pub async fn issue_ws_ticket(
+        &self,
+        session_id: i64,
+        ttl: Duration,
+    ) -> Result<String, sqlx::Error> {
+        let ticket = Uuid::new_v4().to_string();
+        db::create_ws_ticket(&self.db, &ticket, session_id, ttl.as_secs()).await?;
+        Ok(ticket)
+    }
+
+    /// Resolve the session a WebSocket ticket was issued for, without using
+    /// the ticket up. Unknown, expired and already used tickets are all
+    /// `Missing`.
+    pub async fn check_ws_ticket(&self, ticket: &str) -> Result<SessionStatus, sqlx::Error> {
+        match db::find_ws_ticket(&self.db, ticket).await? {
+            Some(token) => self.resolve_session(&token).await,
+            None => Ok(SessionStatus::Missing),
+        }
+    }
+
+    /// Use up a WebSocket ticket. Returns false if it was unknown, expired
+    /// or already used, e.g. by a connection racing this one.
+    pub async fn redeem_ws_ticket(&self, ticket: &str) -> Result<bool, sqlx::Error> {
+        db::consume_ws_ticket(&self.db, ticket).await
+    }
+
+    /// Delete every session past its absolute or idle lifetime, along with
+    /// expired WebSocket tickets, and revoke them like a logout would.
+    /// Returns the number of sessions deleted.
+    pub async fn purge_expired(&self) -> Result<u64, sqlx::Error> {
+        db::delete_expired_ws_tickets(&self.db).await?;
+
+        let mut outbox = self.events.begin().await?;
+        let ids = db::delete_expired_sessions(
+            outbox.conn(),
+            self.lifetimes.absolute.as_secs(),
+            self.lifetimes.idle.as_secs(),
+        )
+        .await?;
+        let count = ids.len() as u64;
+        record_revocations(&mut outbox, ids).await?;
+        outbox.commit().await?;
+        Ok(count)
+    }
+}
+
+/// Record that each of the sessions `ids` has ended
+pub(crate) async fn record_revocations(
+    outbox: &mut Outbox,
+    ids: impl IntoIterator<Item = i64>,
+) -> Result<(), sqlx::Error> {
+    for session_id in ids {
+        outbox
+            .record(&WsEvent::SessionRevoked { session_id })
+            .await?;
+    }
+    Ok(())
+}
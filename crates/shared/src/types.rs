@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use ts_rs::TS;
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
@@ -8,12 +8,232 @@ pub struct Profile {
     pub user_id: i64,
     pub display_name: String,
     pub bio: Option<String>,
+    /// Starts at 1 and goes up by one with every update
+    pub version: i64,
     pub updated_at: String,
 }
 
+impl Profile {
+    /// Bring this copy up to date with a patch made to it
+    pub fn apply(&mut self, patch: ProfilePatch) {
+        if let Some(display_name) = patch.changed_fields.display_name {
+            self.display_name = display_name;
+        }
+        if let Some(bio) = patch.changed_fields.bio {
+            self.bio = Some(bio);
+        }
+        self.version = patch.version;
+        self.updated_at = patch.updated_at;
+    }
+}
+
+/// The profile fields an update set; fields it didn't touch are left out
+#[derive(Debug, Clone, Default, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct ProfileFields {
+    #[ts(optional)]
+    pub display_name: Option<String>,
+    #[ts(optional)]
+    pub bio: Option<String>,
+}
+
+// Hand-written so untouched fields are omitted rather than sent as null;
+// ts-rs doesn't understand `skip_serializing_if`
+impl Serialize for ProfileFields {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let len = usize::from(self.display_name.is_some()) + usize::from(self.bio.is_some());
+        let mut fields = serializer.serialize_struct("ProfileFields", len)?;
+        if let Some(display_name) = &self.display_name {
+            fields.serialize_field("display_name", display_name)?;
+        }
+        if let Some(bio) = &self.bio {
+            fields.serialize_field("bio", bio)?;
+        }
+        fields.end()
+    }
+}
+
+impl ProfileFields {
+    /// Layer a later change on top of this one
+    pub fn merge(&mut self, later: ProfileFields) {
+        if later.display_name.is_some() {
+            self.display_name = later.display_name;
+        }
+        if later.bio.is_some() {
+            self.bio = later.bio;
+        }
+    }
+}
+
+/// A change to an existing profile. It applies to a copy of the profile at
+/// `base_version` and brings it to `version`; a client holding any other
+/// version has missed something and should refresh the profile instead.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct ProfilePatch {
+    pub id: i64,
+    pub user_id: i64,
+    pub base_version: i64,
+    pub version: i64,
+    pub changed_fields: ProfileFields,
+    pub updated_at: String,
+}
+
+impl ProfilePatch {
+    /// Fold a later patch to the same profile into this one, so it goes
+    /// from this one's base version to the later one's version
+    pub fn merge(&mut self, later: ProfilePatch) {
+        self.changed_fields.merge(later.changed_fields);
+        self.version = later.version;
+        self.updated_at = later.updated_at;
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export, export_to = "../../frontend/src/types/bindings/")]
 #[serde(tag = "type", content = "data")]
 pub enum WsEvent {
+    /// A new profile, in full
     Profile(Profile),
+    /// An update to a profile, carrying only the fields that changed
+    ProfilePatch(ProfilePatch),
+    /// The current state of one or more topics, sent when a client connects
+    /// without a resumable `since`, resyncs, or subscribes. `cursor` is the
+    /// log position the snapshot is consistent with.
+    Snapshot { profiles: Vec<Profile>, cursor: i64 },
+    /// A user opened their first WebSocket
+    PresenceJoined { user_id: i64 },
+    /// A user closed their last WebSocket
+    PresenceLeft { user_id: i64 },
+    /// Everyone online right now, replacing whoever the client had as
+    /// online. Sent on subscribing to presence, and when presence changes
+    /// were missed.
+    PresenceSnapshot { user_ids: Vec<i64> },
+    /// A profile was deleted; clients should forget it
+    Deleted { id: i64, user_id: i64 },
+    /// A user's account was removed. Their profile, if they had one, gets
+    /// its own `Deleted` first.
+    Removed { user_id: i64 },
+    /// A login session ended, so connections opened with it must close.
+    /// Carried on the event bus to every instance, but never sent to
+    /// clients.
+    #[ts(skip)]
+    SessionRevoked { session_id: i64 },
+}
+
+/// One of the current user's login sessions
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct SessionInfo {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: String,
+    pub last_seen_at: String,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct SessionsRevoked {
+    pub revoked: i64,
+}
+
+/// Users with at least one open WebSocket
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct OnlineUsers {
+    pub user_ids: Vec<i64>,
+}
+
+/// Single-use credential for opening `/api/ws?ticket=...` without cookies
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct WsTicket {
+    pub ticket: String,
+    pub expires_in_secs: i64,
+}
+
+/// A `WsEvent` tagged with its position in the durable event log. This is
+/// the frame sent over `/api/ws`; reconnect with `?since=<seq>` to resume.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct SequencedEvent {
+    pub seq: i64,
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub event: WsEvent,
+}
+
+/// Client-to-server message on `/api/ws`. The server answers every request
+/// with a `WsReply` carrying the same `request_id`.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct WsRequest {
+    pub request_id: String,
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub command: WsCommand,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+#[serde(tag = "type", content = "data")]
+pub enum WsCommand {
+    /// Start receiving events for these topics (`profiles:*`, `profile:{id}`,
+    /// `user:{id}`). Their current state is sent before the reply.
+    Subscribe {
+        topics: Vec<String>,
+    },
+    Unsubscribe {
+        topics: Vec<String>,
+    },
+    /// Same as `PATCH /api/profiles/{id}`
+    UpdateProfile {
+        id: i64,
+        display_name: Option<String>,
+        bio: Option<String>,
+    },
+}
+
+/// Server's answer to a `WsRequest`. `request_id` is only missing when the
+/// request was too malformed to read it.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct WsReply {
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub outcome: WsOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+#[serde(tag = "type", content = "data")]
+pub enum WsOutcome {
+    Subscribed { topics: Vec<String> },
+    Unsubscribed { topics: Vec<String> },
+    ProfileUpdated(Profile),
+    Error(WsError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+pub struct WsError {
+    pub code: WsErrorCode,
+    pub message: String,
+}
+
+/// Mirrors the HTTP statuses the equivalent REST endpoints would return
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/src/types/bindings/")]
+#[serde(rename_all = "snake_case")]
+pub enum WsErrorCode {
+    BadRequest,
+    Unauthorized,
+    SessionExpired,
+    NotFound,
+    TooManyRequests,
+    Internal,
 }
@@ -4,12 +4,20 @@ const API_BASE = "/api";
 
 // Convert bigint fields from JSON (which returns numbers) to actual bigints
 function parseProfile(data: unknown): Profile {
-  const raw = data as { id: number; user_id: number; display_name: string; bio: string | null; updated_at: string };
+  const raw = data as {
+    id: number;
+    user_id: number;
+    display_name: string;
+    bio: string | null;
+    version: number;
+    updated_at: string;
+  };
   return {
     id: BigInt(raw.id),
     user_id: BigInt(raw.user_id),
     display_name: raw.display_name,
     bio: raw.bio,
+    version: BigInt(raw.version),
     updated_at: raw.updated_at,
   };
 }
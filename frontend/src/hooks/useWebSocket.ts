@@ -1,8 +1,11 @@
 import { useEffect, useRef, useState, useCallback } from "react";
-import type { WsEvent, Profile } from "../types/bindings";
+import type { SequencedEvent, WsEvent, Profile, ProfilePatch } from "../types/bindings";
 
 interface UseWebSocketOptions {
   onProfile?: (profile: Profile) => void;
+  onPatch?: (patch: ProfilePatch) => void;
+  onSnapshot?: (profiles: Profile[]) => void;
+  onDeleted?: (id: bigint) => void;
   reconnectInterval?: number;
 }
 
@@ -12,12 +15,39 @@ interface UseWebSocketReturn {
 }
 
 function parseProfile(data: unknown): Profile {
-  const raw = data as { id: number; user_id: number; display_name: string; bio: string | null; updated_at: string };
+  const raw = data as {
+    id: number;
+    user_id: number;
+    display_name: string;
+    bio: string | null;
+    version: number;
+    updated_at: string;
+  };
   return {
     id: BigInt(raw.id),
     user_id: BigInt(raw.user_id),
     display_name: raw.display_name,
     bio: raw.bio,
+    version: BigInt(raw.version),
+    updated_at: raw.updated_at,
+  };
+}
+
+function parsePatch(data: unknown): ProfilePatch {
+  const raw = data as {
+    id: number;
+    user_id: number;
+    base_version: number;
+    version: number;
+    changed_fields: ProfilePatch["changed_fields"];
+    updated_at: string;
+  };
+  return {
+    id: BigInt(raw.id),
+    user_id: BigInt(raw.user_id),
+    base_version: BigInt(raw.base_version),
+    version: BigInt(raw.version),
+    changed_fields: raw.changed_fields,
     updated_at: raw.updated_at,
   };
 }
@@ -27,20 +57,56 @@ function parseWsEvent(data: unknown): WsEvent {
   if (raw.type === "Profile") {
     return { type: "Profile", data: parseProfile(raw.data) };
   }
+  if (raw.type === "ProfilePatch") {
+    return { type: "ProfilePatch", data: parsePatch(raw.data) };
+  }
+  if (raw.type === "Snapshot") {
+    const snapshot = raw.data as { profiles: unknown[]; cursor: number };
+    return {
+      type: "Snapshot",
+      data: { profiles: snapshot.profiles.map(parseProfile), cursor: BigInt(snapshot.cursor) },
+    };
+  }
+  if (raw.type === "PresenceJoined" || raw.type === "PresenceLeft") {
+    const presence = raw.data as { user_id: number };
+    return { type: raw.type, data: { user_id: BigInt(presence.user_id) } };
+  }
+  if (raw.type === "PresenceSnapshot") {
+    const snapshot = raw.data as { user_ids: number[] };
+    return { type: "PresenceSnapshot", data: { user_ids: snapshot.user_ids.map(BigInt) } };
+  }
+  if (raw.type === "Deleted") {
+    const deleted = raw.data as { id: number; user_id: number };
+    return { type: "Deleted", data: { id: BigInt(deleted.id), user_id: BigInt(deleted.user_id) } };
+  }
+  if (raw.type === "Removed") {
+    const removed = raw.data as { user_id: number };
+    return { type: "Removed", data: { user_id: BigInt(removed.user_id) } };
+  }
   throw new Error(`Unknown event type: ${raw.type}`);
 }
 
+function parseSequencedEvent(data: unknown): SequencedEvent {
+  const raw = data as { seq: number };
+  return { seq: BigInt(raw.seq), ...parseWsEvent(data) };
+}
+
 export function useWebSocket(options: UseWebSocketOptions = {}): UseWebSocketReturn {
-  const { onProfile, reconnectInterval = 3000 } = options;
+  const { onProfile, onPatch, onSnapshot, onDeleted, reconnectInterval = 3000 } = options;
   const [isConnected, setIsConnected] = useState(false);
   const [error, setError] = useState<string | null>(null);
   const wsRef = useRef<WebSocket | null>(null);
   const reconnectTimeoutRef = useRef<ReturnType<typeof setTimeout> | null>(null);
   const shouldReconnectRef = useRef(true);
+  // Last sequence number seen, so a reconnect only replays what was missed
+  const lastSeqRef = useRef<bigint | null>(null);
+  // Version of every profile we hold, to spot patches we can't apply
+  const versionsRef = useRef<Map<bigint, bigint>>(new Map());
 
   const connect = useCallback(() => {
     const protocol = window.location.protocol === "https:" ? "wss:" : "ws:";
-    const wsUrl = `${protocol}//${window.location.host}/api/ws`;
+    const since = lastSeqRef.current === null ? "" : `&since=${lastSeqRef.current}`;
+    const wsUrl = `${protocol}//${window.location.host}/api/ws?topics=profiles:*${since}`;
 
     const ws = new WebSocket(wsUrl);
     wsRef.current = ws;
@@ -53,11 +119,37 @@ export function useWebSocket(options: UseWebSocketOptions = {}): UseWebSocketRet
     ws.onmessage = (event) => {
       try {
         const data = JSON.parse(event.data);
-        const wsEvent = parseWsEvent(data);
-
-        // Single handler for all profile events (initial state and updates)
-        if (wsEvent.type === "Profile" && onProfile) {
-          onProfile(wsEvent.data);
+        const wsEvent = parseSequencedEvent(data);
+        lastSeqRef.current = wsEvent.seq;
+
+        const versions = versionsRef.current;
+
+        // The full state arrives as one snapshot, then updates one by one
+        if (wsEvent.type === "Snapshot") {
+          versions.clear();
+          wsEvent.data.profiles.forEach((p) => versions.set(p.id, p.version));
+          onSnapshot?.(wsEvent.data.profiles);
+        } else if (wsEvent.type === "Profile") {
+          versions.set(wsEvent.data.id, wsEvent.data.version);
+          onProfile?.(wsEvent.data);
+        } else if (wsEvent.type === "ProfilePatch") {
+          const patch = wsEvent.data;
+          const held = versions.get(patch.id);
+          if (held !== undefined && patch.version <= held) {
+            // Nothing we don't already have
+            return;
+          }
+          if (held === undefined || patch.base_version > held) {
+            // We missed an update; start over with a fresh snapshot
+            lastSeqRef.current = null;
+            ws.close();
+            return;
+          }
+          versions.set(patch.id, patch.version);
+          onPatch?.(patch);
+        } else if (wsEvent.type === "Deleted") {
+          versions.delete(wsEvent.data.id);
+          onDeleted?.(wsEvent.data.id);
         }
       } catch (e) {
         console.error("Failed to parse WebSocket message:", e);
@@ -72,7 +164,7 @@ export function useWebSocket(options: UseWebSocketOptions = {}): UseWebSocketRet
       setIsConnected(false);
       wsRef.current = null;
     };
-  }, [onProfile]);
+  }, [onProfile, onPatch, onSnapshot, onDeleted]);
 
   // Handle reconnection separately
   useEffect(() => {
@@ -1,18 +1,49 @@
 import { useState, useCallback } from "react"
 import { useWebSocket } from "@/hooks/useWebSocket"
 import { Card, CardContent, CardDescription, CardHeader, CardTitle } from "@/components/ui/card"
-import type { Profile } from "@/types/bindings"
+import type { Profile, ProfilePatch } from "@/types/bindings"
 
 export function ProfilesPage() {
   const [profiles, setProfiles] = useState<Map<bigint, Profile>>(new Map())
+  const [isLoaded, setIsLoaded] = useState(false)
+
+  // The snapshot replaces everything we have, all at once
+  const handleSnapshot = useCallback((snapshot: Profile[]) => {
+    setProfiles(new Map(snapshot.map((profile) => [profile.id, profile])))
+    setIsLoaded(true)
+  }, [])
 
-  // Single handler for all profile events (initial state AND updates)
   const handleProfile = useCallback((profile: Profile) => {
     setProfiles((prev) => new Map(prev).set(profile.id, profile))
   }, [])
 
+  // The hook only hands us patches that apply to the version we hold
+  const handlePatch = useCallback((patch: ProfilePatch) => {
+    setProfiles((prev) => {
+      const profile = prev.get(patch.id)
+      if (!profile) return prev
+      return new Map(prev).set(patch.id, {
+        ...profile,
+        ...patch.changed_fields,
+        version: patch.version,
+        updated_at: patch.updated_at,
+      })
+    })
+  }, [])
+
+  const handleDeleted = useCallback((id: bigint) => {
+    setProfiles((prev) => {
+      const next = new Map(prev)
+      next.delete(id)
+      return next
+    })
+  }, [])
+
   const { isConnected } = useWebSocket({
     onProfile: handleProfile,
+    onPatch: handlePatch,
+    onSnapshot: handleSnapshot,
+    onDeleted: handleDeleted,
   })
 
   const profileList = Array.from(profiles.values())
@@ -34,8 +65,8 @@ export function ProfilesPage() {
         </div>
       </div>
 
-      {!isConnected && profiles.size === 0 ? (
-        <div className="text-center text-muted-foreground">Connecting...</div>
+      {!isLoaded ? (
+        <div className="text-center text-muted-foreground">Loading...</div>
       ) : profileList.length === 0 ? (
         <Card>
           <CardContent className="py-8 text-center text-muted-foreground">
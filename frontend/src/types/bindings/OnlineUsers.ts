@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Users with at least one open WebSocket
+ */
+export type OnlineUsers = { user_ids: Array<bigint>, };
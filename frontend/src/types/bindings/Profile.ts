@@ -1,3 +1,7 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export type Profile = { id: bigint, user_id: bigint, display_name: string, bio: string | null, updated_at: string, };
+export type Profile = { id: bigint, user_id: bigint, display_name: string, bio: string | null, 
+/**
+ * Starts at 1 and goes up by one with every update
+ */
+version: bigint, updated_at: string, };
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * The profile fields an update set; fields it didn't touch are left out
+ */
+export type ProfileFields = { display_name?: string, bio?: string, };
@@ -0,0 +1,9 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ProfileFields } from "./ProfileFields";
+
+/**
+ * A change to an existing profile. It applies to a copy of the profile at
+ * `base_version` and brings it to `version`; a client holding any other
+ * version has missed something and should refresh the profile instead.
+ */
+export type ProfilePatch = { id: bigint, user_id: bigint, base_version: bigint, version: bigint, changed_fields: ProfileFields, updated_at: string, };
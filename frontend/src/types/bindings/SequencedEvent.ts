@@ -0,0 +1,9 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Profile } from "./Profile";
+import type { ProfilePatch } from "./ProfilePatch";
+
+/**
+ * A `WsEvent` tagged with its position in the durable event log. This is
+ * the frame sent over `/api/ws`; reconnect with `?since=<seq>` to resume.
+ */
+export type SequencedEvent = { seq: bigint, } & ({ "type": "Profile", "data": Profile } | { "type": "ProfilePatch", "data": ProfilePatch } | { "type": "Snapshot", "data": { profiles: Array<Profile>, cursor: bigint, } } | { "type": "PresenceJoined", "data": { user_id: bigint, } } | { "type": "PresenceLeft", "data": { user_id: bigint, } } | { "type": "PresenceSnapshot", "data": { user_ids: Array<bigint>, } } | { "type": "Deleted", "data": { id: bigint, user_id: bigint, } } | { "type": "Removed", "data": { user_id: bigint, } });
@@ -0,0 +1,10 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * One of the current user's login sessions
+ */
+export type SessionInfo = { id: bigint, user_agent: string | null, ip_address: string | null, created_at: string, last_seen_at: string, 
+/**
+ * Whether this is the session making the request
+ */
+current: boolean, };
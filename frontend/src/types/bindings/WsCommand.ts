@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type WsCommand = { "type": "Subscribe", "data": { topics: Array<string>, } } | { "type": "Unsubscribe", "data": { topics: Array<string>, } } | { "type": "UpdateProfile", "data": { id: bigint, display_name: string | null, bio: string | null, } };
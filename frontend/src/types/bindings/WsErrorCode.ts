@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Mirrors the HTTP statuses the equivalent REST endpoints would return
+ */
+export type WsErrorCode = "bad_request" | "unauthorized" | "session_expired" | "not_found" | "too_many_requests" | "internal";
@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Profile } from "./Profile";
+import type { WsError } from "./WsError";
+
+export type WsOutcome = { "type": "Subscribed", "data": { topics: Array<string>, } } | { "type": "Unsubscribed", "data": { topics: Array<string>, } } | { "type": "ProfileUpdated", "data": Profile } | { "type": "Error", "data": WsError };
@@ -0,0 +1,9 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Profile } from "./Profile";
+import type { WsError } from "./WsError";
+
+/**
+ * Server's answer to a `WsRequest`. `request_id` is only missing when the
+ * request was too malformed to read it.
+ */
+export type WsReply = { request_id: string | null, } & ({ "type": "Subscribed", "data": { topics: Array<string>, } } | { "type": "Unsubscribed", "data": { topics: Array<string>, } } | { "type": "ProfileUpdated", "data": Profile } | { "type": "Error", "data": WsError });
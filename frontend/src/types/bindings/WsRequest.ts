@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Client-to-server message on `/api/ws`. The server answers every request
+ * with a `WsReply` carrying the same `request_id`.
+ */
+export type WsRequest = { request_id: string, } & ({ "type": "Subscribe", "data": { topics: Array<string>, } } | { "type": "Unsubscribe", "data": { topics: Array<string>, } } | { "type": "UpdateProfile", "data": { id: bigint, display_name: string | null, bio: string | null, } });
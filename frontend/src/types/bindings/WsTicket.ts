@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Single-use credential for opening `/api/ws?ticket=...` without cookies
+ */
+export type WsTicket = { ticket: string, expires_in_secs: bigint, };
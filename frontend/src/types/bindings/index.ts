@@ -1,2 +1,15 @@
+export type { OnlineUsers } from "./OnlineUsers";
 export type { Profile } from "./Profile";
+export type { ProfileFields } from "./ProfileFields";
+export type { ProfilePatch } from "./ProfilePatch";
+export type { SequencedEvent } from "./SequencedEvent";
+export type { SessionInfo } from "./SessionInfo";
+export type { SessionsRevoked } from "./SessionsRevoked";
+export type { WsCommand } from "./WsCommand";
+export type { WsError } from "./WsError";
+export type { WsErrorCode } from "./WsErrorCode";
 export type { WsEvent } from "./WsEvent";
+export type { WsOutcome } from "./WsOutcome";
+export type { WsReply } from "./WsReply";
+export type { WsRequest } from "./WsRequest";
+export type { WsTicket } from "./WsTicket";
@@ -0,0 +1,7 @@
+-- Short-lived, single-use tickets for authenticating WebSocket upgrades
+-- from clients that can't send the session cookie
+CREATE TABLE IF NOT EXISTS ws_tickets (
+    ticket TEXT PRIMARY KEY,
+    session_id INTEGER NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    expires_at TEXT NOT NULL
+);
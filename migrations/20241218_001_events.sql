@@ -0,0 +1,7 @@
+-- Durable, sequenced log of realtime events (payload is a JSON WsEvent).
+-- AUTOINCREMENT so sequence numbers are never reused after compaction.
+CREATE TABLE IF NOT EXISTS events (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    payload TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
@@ -0,0 +1,3 @@
+-- Bumped on every profile update, so clients applying field-level patches
+-- can tell when they've missed one.
+ALTER TABLE profiles ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
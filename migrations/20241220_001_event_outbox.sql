@@ -0,0 +1,10 @@
+-- The events table doubles as a transactional outbox: events are inserted
+-- in the same transaction as the change they describe, and a relay
+-- broadcasts them and stamps `delivered_at`. Everything already in the log
+-- has been broadcast.
+ALTER TABLE events ADD COLUMN delivered_at TEXT;
+UPDATE events SET delivered_at = created_at;
+CREATE INDEX IF NOT EXISTS idx_events_undelivered ON events(seq) WHERE delivered_at IS NULL;
+-- The process that committed each event. A relay whose bus only reaches
+-- its own process's connections publishes only its own process's events.
+ALTER TABLE events ADD COLUMN origin TEXT;